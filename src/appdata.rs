@@ -1,24 +1,174 @@
-use crate::config::Config;
-use crate::services::ansible::AnsibleService;
-use crate::DnsService;
-use actix_web::web;
-use std::sync::Arc;
+use crate::config::{Config, ConflictPolicy};
+use crate::logging::JobContext;
+use crate::metrics;
+use crate::services::access::AccessList;
+use crate::services::addresses::Addresses;
+use crate::services::auth::Auth;
+use crate::services::jobs::PendingJobs;
+use crate::services::naming::{NameError, Naming};
+use crate::services::pipeline::{Pipeline, PipelineError};
+use crate::services::proxies::TrustedProxies;
+use crate::services::registry::Registry;
+use crate::services::throttle::Throttle;
+use crate::services::{Service, Target};
+use crate::util::Ready;
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, FromRequest, HttpRequest};
+use log::{error, warn};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use thiserror::Error;
 
-pub type WebData = web::Data<Arc<ApplicationData>>;
+#[derive(Debug, Error)]
+pub enum AppDataError {
+    #[error("Failed to create service pipeline: {0}")]
+    Pipeline(#[from] PipelineError),
+    #[error("Invalid domain configuration: {0}")]
+    Naming(#[from] NameError),
+    #[error("The registry's conflict policy is 'approve', but [approval] is not configured")]
+    ApprovalNotConfigured,
+}
 
 #[derive(Debug)]
 pub struct ApplicationData {
-    pub config: Config,
-    pub ansible: AnsibleService,
-    pub dns: DnsService,
+    pub pipeline: Pipeline,
+    pub naming: Naming,
+    pub addresses: Addresses,
+    pub auth: Option<Auth>,
+    pub access: AccessList,
+    pub proxies: TrustedProxies,
+    pub registry: Option<Registry>,
+    pub pending: Option<PendingJobs>,
+    pub throttle: Throttle,
+    /// Whether all jobs are dry runs, see `global.dry_run`
+    pub dry_run: bool,
 }
 
 impl ApplicationData {
-    pub fn new(config: Config, ansible: AnsibleService, dns: DnsService) -> Arc<Self> {
-        Arc::new(Self {
-            config,
-            ansible,
-            dns,
-        })
+    pub fn new(config: &Config) -> Result<Arc<Self>, AppDataError> {
+        let pending = config.approval.as_ref().map(PendingJobs::new).transpose()?;
+        Self::with_state(config, pending, Throttle::new(&config.throttle))
+    }
+
+    /// Create the application data for a reloaded configuration.
+    /// Pending jobs and the state of the throttle are carried over
+    pub fn reload(&self, config: &Config) -> Result<Arc<Self>, AppDataError> {
+        let pending = match (&self.pending, &config.approval) {
+            (Some(pending), Some(approval)) => Some(pending.reconfigure(approval)?),
+            (None, Some(approval)) => Some(PendingJobs::new(approval)?),
+            (Some(pending), None) => {
+                let count = pending.len();
+                if count > 0 {
+                    warn!(
+                        "Approval is no longer configured, dropping {} pending job(s)",
+                        count
+                    );
+                }
+                None
+            }
+            (None, None) => None,
+        };
+        Self::with_state(config, pending, self.throttle.reconfigure(&config.throttle))
+    }
+
+    fn with_state(
+        config: &Config,
+        pending: Option<PendingJobs>,
+        throttle: Throttle,
+    ) -> Result<Arc<Self>, AppDataError> {
+        let approves_conflicts = config
+            .registry
+            .as_ref()
+            .map(|registry| registry.on_conflict == ConflictPolicy::Approve)
+            .unwrap_or(false);
+        if approves_conflicts && config.approval.is_none() {
+            return Err(AppDataError::ApprovalNotConfigured);
+        }
+
+        Ok(Arc::new(Self {
+            pipeline: Pipeline::new(config)?,
+            naming: Naming::new(config)?,
+            addresses: Addresses::new(&config.global),
+            auth: config.auth.as_ref().map(Auth::new),
+            access: AccessList::new(&config.global),
+            proxies: TrustedProxies::new(&config.global),
+            registry: config.registry.as_ref().map(Registry::new),
+            pending,
+            throttle,
+            dry_run: config.global.dry_run,
+        }))
+    }
+
+    /// Run the pipeline for `target` on a new thread.
+    /// The job must have been admitted or started by the throttle
+    pub fn spawn_job(self: &Arc<Self>, target: Target, context: JobContext) {
+        let data = self.clone();
+        metrics::JOBS_IN_FLIGHT.inc();
+        thread::Builder::new()
+            .name(format!("phone-home-{}-{}", &target.fqdn, &target.ip))
+            .spawn(move || {
+                let _context = context.enter();
+                let result = data.pipeline.run(&target);
+                if let Err(e) = &result {
+                    error!("Pipeline failed for {:?}: {:?}", target, e);
+                }
+                // A dry run does not start the cool-down, so the machine can be provisioned right after
+                data.throttle
+                    .finish(&target, result.is_ok() && !target.dry_run);
+                metrics::JOBS_IN_FLIGHT.dec();
+            })
+            .expect("Spawning thread");
+    }
+}
+
+/// The application data used for new requests.
+/// It is replaced when the configuration is reloaded, running jobs keep the data they were started with
+#[derive(Debug)]
+pub struct CurrentData(RwLock<Arc<ApplicationData>>);
+
+impl CurrentData {
+    pub fn new(data: Arc<ApplicationData>) -> Self {
+        Self(RwLock::new(data))
+    }
+
+    pub fn get(&self) -> Arc<ApplicationData> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, data: Arc<ApplicationData>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = data;
+    }
+}
+
+/// The application data as it was when the request was received
+pub struct WebData(Arc<ApplicationData>);
+
+impl WebData {
+    /// The current application data, if it was added to the app
+    pub fn current(req: &HttpRequest) -> Option<Self> {
+        req.app_data::<web::Data<CurrentData>>()
+            .map(|current| Self(current.get()))
+    }
+}
+
+impl Deref for WebData {
+    type Target = Arc<ApplicationData>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for WebData {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, actix_web::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let result = Self::current(req)
+            .ok_or_else(|| ErrorInternalServerError("Application data is not configured"));
+        Ready::new(result)
     }
 }
@@ -0,0 +1,239 @@
+use crate::config::Config;
+use crate::error::ServiceError;
+use crate::logging::JobContext;
+use crate::opts::{DnsCommand, InventoryCommand, JobsCommand};
+use crate::services::addresses::Addresses;
+use crate::services::ansible::{AnsibleError, AnsibleService};
+use crate::services::dns::{DnsError, DnsService};
+use crate::services::jobs::PendingJobInfo;
+use crate::services::naming::{DomainName, NameError, Naming};
+use crate::services::pipeline::{Pipeline, PipelineError};
+use crate::services::{Service, Target};
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Approval is not configured")]
+    ApprovalNotConfigured,
+    #[error("Server returned HTTP status {0}: {1}")]
+    Status(u16, String),
+    #[error("Failed to reach server: {0}")]
+    Transport(Box<ureq::Transport>),
+    #[error("Invalid response: {0}")]
+    Response(#[from] std::io::Error),
+    #[error("[{0}] is not configured")]
+    NotConfigured(&'static str),
+    #[error("Invalid domain name: {0}")]
+    Name(#[from] NameError),
+    #[error("Failed to create service pipeline: {0}")]
+    Pipeline(#[from] PipelineError),
+    #[error("{0}")]
+    Service(#[from] ServiceError),
+    #[error("DNS error: {0}")]
+    Dns(#[from] DnsError),
+    #[error("Ansible error: {0}")]
+    Ansible(#[from] AnsibleError),
+}
+
+impl From<ureq::Error> for CliError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(code, response) => {
+                Self::Status(code, response.into_string().unwrap_or_default())
+            }
+            ureq::Error::Transport(t) => Self::Transport(Box::new(t)),
+        }
+    }
+}
+
+/// Manage pending jobs through the API of a running server
+pub fn jobs(config: &Config, url: Option<&str>, command: &JobsCommand) -> Result<(), CliError> {
+    let token = config
+        .approval
+        .as_ref()
+        .map(|approval| approval.admin_token.as_str())
+        .ok_or(CliError::ApprovalNotConfigured)?;
+    let url = match url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => server_url(config),
+    };
+    let authorization = format!("Bearer {}", token);
+
+    match command {
+        JobsCommand::List => {
+            let jobs: Vec<PendingJobInfo> = ureq::get(&format!("{}/jobs", url))
+                .set("Authorization", &authorization)
+                .call()?
+                .into_json()?;
+
+            if jobs.is_empty() {
+                println!("No jobs are awaiting approval");
+            }
+
+            for job in jobs {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    job.id,
+                    job.fqdn,
+                    job.addresses
+                        .iter()
+                        .map(|ip| ip.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    job.reason
+                );
+            }
+        }
+        JobsCommand::Approve { id } => {
+            ureq::post(&format!("{}/jobs/{}/approve", url, id))
+                .set("Authorization", &authorization)
+                .call()?;
+            println!("Approved job {}", id);
+        }
+        JobsCommand::Reject { id } => {
+            ureq::post(&format!("{}/jobs/{}/reject", url, id))
+                .set("Authorization", &authorization)
+                .call()?;
+            println!("Rejected job {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the pipeline for a machine once, as if it phoned home from `ip`
+pub fn provision(
+    config: &Config,
+    hostname: &str,
+    ip: IpAddr,
+    fqdn: Option<&str>,
+) -> Result<(), CliError> {
+    let target = target(config, hostname, ip, fqdn)?;
+    let pipeline = Pipeline::new(config)?;
+
+    let context = JobContext::new(hostname, ip);
+    let _context = context.enter();
+    println!("Provisioning {} ({})", target.fqdn, target.ip);
+    if target.dry_run {
+        for (step, report) in pipeline.dry_run(&target)? {
+            println!("Dry run of {} step: {}", step, report);
+        }
+        println!("Dry run of {} completed", target.fqdn);
+    } else {
+        pipeline.run(&target)?;
+        println!("Provisioned {}", target.fqdn);
+    }
+    Ok(())
+}
+
+/// Manage the hosts in the Ansible inventory
+pub fn inventory(config: &Config, command: &InventoryCommand) -> Result<(), CliError> {
+    let ansible = config
+        .ansible
+        .as_ref()
+        .ok_or(CliError::NotConfigured("ansible"))?;
+    let ansible = AnsibleService::new(ansible)?;
+
+    match command {
+        InventoryCommand::List => {
+            for (group, host) in ansible.hosts()? {
+                println!("{}\t{}", host, group);
+            }
+        }
+        InventoryCommand::Add { name } if config.global.dry_run => {
+            if ansible.hosts()?.iter().any(|(_, host)| host == name) {
+                println!("Dry run: {} is already in the inventory", name);
+            } else {
+                println!("Dry run: not adding {} to the inventory", name);
+            }
+        }
+        InventoryCommand::Remove { name } if config.global.dry_run => {
+            if ansible.hosts()?.iter().any(|(_, host)| host == name) {
+                println!("Dry run: not removing {} from the inventory", name);
+            } else {
+                println!("Dry run: {} is not in the inventory", name);
+            }
+        }
+        InventoryCommand::Add { name } => {
+            ansible.add_host(name)?;
+            println!("Added {} to the inventory", name);
+        }
+        InventoryCommand::Remove { name } => {
+            if ansible.remove_host(name)? {
+                println!("Removed {} from the inventory", name);
+            } else {
+                println!("{} is not in the inventory", name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Manage DNS records
+pub fn dns(config: &Config, command: &DnsCommand) -> Result<(), CliError> {
+    let dns = config.dns.as_ref().ok_or(CliError::NotConfigured("dns"))?;
+    let dns = DnsService::new(dns, &config.global)?;
+
+    match command {
+        DnsCommand::Add { hostname, ip, fqdn } => {
+            let target = target(config, hostname, *ip, fqdn.as_deref())?;
+            if target.dry_run {
+                println!("Dry run: {}", dns.dry_run(&target)?);
+                return Ok(());
+            }
+            dns.run(&target)?;
+            println!(
+                "Added {} ({})",
+                target.fqdn,
+                target
+                    .addresses
+                    .iter()
+                    .map(|ip| ip.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+        }
+        DnsCommand::Remove { fqdn, zone } => {
+            let fqdn = fqdn.parse::<DomainName>()?;
+            let zone = zone.as_deref().map(str::parse::<DomainName>).transpose()?;
+            if config.global.dry_run {
+                println!("Dry run: {}", dns.describe_remove(&fqdn, zone.as_ref())?);
+            } else {
+                dns.remove(&fqdn, zone.as_ref())?;
+                println!("Removed {}", fqdn);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The target for a machine, as if it phoned home from `ip`. It is a dry run if `global.dry_run` is set
+fn target(
+    config: &Config,
+    hostname: &str,
+    ip: IpAddr,
+    fqdn: Option<&str>,
+) -> Result<Target, CliError> {
+    let addresses = Addresses::new(&config.global).resolve(ip, &[]);
+    let mut target = Naming::new(config)?.target(&ip.to_string(), addresses, hostname, fqdn)?;
+    target.dry_run = config.global.dry_run;
+    Ok(target)
+}
+
+/// The URL of the local server, based on the first address it listens on
+fn server_url(config: &Config) -> String {
+    let scheme = match config.global.tls {
+        Some(_) => "https",
+        None => "http",
+    };
+    let host = match config.global.listen_addresses().first().map(|x| x.ip()) {
+        Some(ip) if !ip.is_unspecified() => ip,
+        Some(IpAddr::V6(_)) if config.global.listen.len() == 1 => "::1".parse().unwrap(),
+        _ => "127.0.0.1".parse().unwrap(),
+    };
+
+    format!("{}://{}", scheme, SocketAddr::new(host, config.global.port))
+}
@@ -1,30 +1,403 @@
+use ipnet::IpNet;
 use log::{debug, trace, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
-    pub ansible: AnsibleConfig,
-    pub dns: DnsConfig,
+    pub ansible: Option<AnsibleConfig>,
+    /// Hold jobs until an operator approves them
+    pub approval: Option<ApprovalConfig>,
+    /// Authentication of phone-home requests. If not set, all requests are accepted
+    pub auth: Option<AuthConfig>,
+    pub dns: Option<DnsConfig>,
     pub global: GlobalConfig,
+    /// Registry of which machine owns which name. If not set, any machine may claim any name
+    pub registry: Option<RegistryConfig>,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    #[serde(default = "default_pipeline")]
+    pub pipeline: Vec<PipelineEntry>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ansible: Some(AnsibleConfig::default()),
+            approval: None,
+            auth: None,
+            dns: Some(DnsConfig::default()),
+            global: GlobalConfig::default(),
+            registry: None,
+            throttle: ThrottleConfig::default(),
+            pipeline: default_pipeline(),
+        }
+    }
+}
+
+/// A service to run when a machine phones home.
+/// Services are run in the order in which they are configured
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum PipelineEntry {
+    /// Create a DNS record, configured by the `[dns]` section
+    Dns,
+    /// Run Ansible playbooks, configured by the `[ansible]` section
+    Ansible,
+    /// POST a description of the machine to an HTTP endpoint
+    Webhook(WebhookConfig),
+    /// Run a command
+    Command(CommandConfig),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CommandConfig {
+    /// The program to run
+    pub command: PathBuf,
+    /// Templated arguments passed to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Extra (templated) environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// The working directory of the program
+    pub workdir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Templated request body. If not set, a JSON description of the machine is sent
+    pub body: Option<String>,
+    /// Extra (templated) request headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Secret used to sign the request body with HMAC-SHA256
+    pub secret: Option<String>,
+    /// When the webhook should be called
+    #[serde(default)]
+    pub on: WebhookTrigger,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// Delay between retries, in seconds
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay: u64,
+    /// Request timeout, in seconds
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookTrigger {
+    /// Only when all previous services succeeded
+    #[default]
+    Success,
+    /// Only when a previous service failed
+    Failure,
+    /// Regardless of the outcome of previous services
+    Always,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct GlobalConfig {
     pub domain: String,
+    /// Deprecated, Ordin listens on both IPv4 and IPv6 unless configured otherwise in `listen`
+    #[serde(default)]
     pub ipv6: bool,
+    /// Only report the changes the services would make, instead of making them
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Register the addresses posted in the `ipv4` and `ipv6` fields. They must be allowed by `allow` and `deny`
+    #[serde(default)]
+    pub accept_posted_addresses: bool,
+    /// The header trusted proxies forward the client address in
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// The addresses to listen on. Defaults to all IPv4 and IPv6 addresses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<IpAddr>,
+    /// Derive additional addresses of a machine from the address it phoned home from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub address_mappings: Vec<AddressMapping>,
+    /// Networks allowed to phone home. If empty, all networks not denied are allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    /// Networks not allowed to phone home, takes precedence over `allow`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+    /// Reverse proxies whose `forwarded_header` is honored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Serve HTTPS instead of HTTP
+    pub tls: Option<TlsConfig>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+impl GlobalConfig {
+    /// The socket addresses to listen on
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            return vec![
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port),
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), self.port),
+            ];
+        }
+
+        self.listen
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Seconds after a successful job during which phone-homes of the same machine are ignored
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// The maximum number of jobs started per minute. 0 disables the limit
+    #[serde(default)]
+    pub rate_limit: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            cooldown: default_cooldown(),
+            rate_limit: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RegistryConfig {
+    /// The JSON file the registry is stored in
+    pub path: PathBuf,
+    /// What to do when a machine claims a name registered to another machine
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Refuse the request. An operator can release the name by removing it from the registry
+    #[default]
+    Refuse,
+    /// Refuse the request, unless it explicitly asks to take the name over with `override=true`.
+    /// The flag is only honored for requests authenticated with `[auth]`
+    Override,
+    /// Hold the job until an operator approves it, see `[approval]`
+    Approve,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApprovalConfig {
+    /// The token operators use to list, approve and reject pending jobs
+    pub admin_token: String,
+    /// Whether new machines require approval. If false, only conflicting claims in the registry do
+    #[serde(default = "default_true")]
+    pub require: bool,
+    /// Only require approval for machines in these zones. If empty, all machines require approval
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<String>,
+    /// Seconds after which pending jobs expire
+    #[serde(default = "default_approval_expiry")]
+    pub expire_after: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain
+    pub cert: PathBuf,
+    /// PEM file containing the PKCS#8 or RSA private key
+    pub key: PathBuf,
+    /// PEM file containing the CA certificates used to verify client certificates.
+    /// If set, clients must present a certificate signed by one of these CAs
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuthConfig {
+    /// The shared secret machines must present when phoning home
+    pub secret: String,
+    /// Require a token signed with the secret and carrying an expiry time,
+    /// instead of the secret itself
+    #[serde(default)]
+    pub signed: bool,
+}
+
+/// Maps an address in `from` to the address with the same host part in `to`.
+/// E.g. with `from = 10.1.0.0/16` and `to = 2001:db8:1::/64`, `10.1.2.3` maps to `2001:db8:1::203`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AddressMapping {
+    pub from: IpNet,
+    pub to: IpNet,
+}
+
+/// The DNS configuration.
+/// Either a single zone is configured with the top-level fields, or multiple zones in `zones`
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DnsConfig {
+    /// The DNS server, used by the nsupdate backend
+    #[serde(default)]
     pub server: String,
+    #[serde(default)]
     pub zone_name: String,
+    #[serde(default = "default_ttl")]
     pub ttl: u64,
+    pub key_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<ZoneConfig>,
+    #[serde(default)]
+    pub backend: DnsBackendConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ZoneConfig {
+    pub zone_name: String,
+    /// The domain machines in this zone are named under. Defaults to the zone name
+    pub domain: Option<String>,
+    /// The DNS server, used by the nsupdate backend
+    #[serde(default)]
+    pub server: String,
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// TSIG key file, used by the nsupdate backend
+    pub key_file: Option<PathBuf>,
+    /// Hostname suffixes which place a machine in this zone. The zone's domain always matches
+    #[serde(default)]
+    pub suffixes: Vec<String>,
+    /// Source networks which place a machine in this zone
+    #[serde(default)]
+    pub subnets: Vec<IpNet>,
+    #[serde(default)]
+    pub backend: DnsBackendConfig,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            server: String::new(),
+            zone_name: String::new(),
+            ttl: default_ttl(),
+            key_file: None,
+            zones: Vec::new(),
+            backend: DnsBackendConfig::default(),
+        }
+    }
+}
+
+impl DnsConfig {
+    /// The configured zones. If no zones are configured explicitly,
+    /// the top-level fields make up a single zone under the global domain
+    pub fn zones(&self, global: &GlobalConfig) -> Vec<ZoneConfig> {
+        if !self.zones.is_empty() {
+            return self.zones.clone();
+        }
+
+        let zone_name = if self.zone_name.is_empty() {
+            global.domain.clone()
+        } else {
+            self.zone_name.clone()
+        };
+
+        vec![ZoneConfig {
+            zone_name,
+            domain: Some(global.domain.clone()),
+            server: self.server.clone(),
+            ttl: self.ttl,
+            key_file: self.key_file.clone(),
+            suffixes: Vec::new(),
+            subnets: Vec::new(),
+            backend: self.backend.clone(),
+        }]
+    }
+}
+
+impl ZoneConfig {
+    /// The domain machines in this zone are named under
+    pub fn domain(&self) -> &str {
+        self.domain.as_deref().unwrap_or(&self.zone_name)
+    }
+}
+
+/// The way DNS records are created
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DnsBackendConfig {
+    /// Send a dynamic update to `server` using nsupdate
+    #[default]
+    Nsupdate,
+    /// Maintain a managed block in a hosts-style file, e.g. `/etc/hosts` or a dnsmasq `addn-hosts` file
+    Hosts(HostsBackendConfig),
+    /// Edit a BIND zone file directly
+    Zonefile(ZonefileBackendConfig),
+    /// Use the PowerDNS HTTP API
+    Powerdns(PowerDnsBackendConfig),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PowerDnsBackendConfig {
+    /// The base URL of the API, e.g. `http://127.0.0.1:8081`
+    pub url: String,
+    pub api_key: String,
+    #[serde(default = "default_powerdns_server_id")]
+    pub server_id: String,
+    /// Request timeout, in seconds
+    #[serde(default = "default_powerdns_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ZonefileBackendConfig {
+    pub path: PathBuf,
+    /// How the SOA serial is incremented
+    #[serde(default)]
+    pub serial: SerialScheme,
+    /// Command validating the updated zone file before it is put in place, e.g. `named-checkzone`.
+    /// `{{path}}` is replaced with the path to the updated file
+    pub check_command: Option<Vec<String>>,
+    /// Command to run after the zone file has been updated, e.g. `rndc reload`
+    pub reload_command: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: <client>, <proxy>`
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=<client>, for=<proxy>`
+    Forwarded,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialScheme {
+    /// `YYYYMMDDnn`
+    #[default]
+    Date,
+    /// Increment the serial by one
+    Integer,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HostsBackendConfig {
+    pub path: PathBuf,
+    /// Command to run after the file has been updated
+    pub reload_command: Option<Vec<String>>,
+    /// Signal to send to the process in `pid_file` after the file has been updated, e.g. `HUP`
+    pub reload_signal: Option<String>,
+    pub pid_file: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -37,10 +410,50 @@ pub struct AnsibleConfig {
     pub play_logdir: PathBuf,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_approval_expiry() -> u64 {
+    86400
+}
+
+fn default_cooldown() -> u64 {
+    300
+}
+
 fn default_port() -> u16 {
     4040
 }
 
+fn default_pipeline() -> Vec<PipelineEntry> {
+    vec![PipelineEntry::Dns, PipelineEntry::Ansible]
+}
+
+fn default_ttl() -> u64 {
+    3600
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_retry_delay() -> u64 {
+    5
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+fn default_powerdns_server_id() -> String {
+    "localhost".to_string()
+}
+
+fn default_powerdns_timeout() -> u64 {
+    10
+}
+
 fn default_play_logdir() -> PathBuf {
     PathBuf::from("/var/log/ordin/")
 }
@@ -53,30 +466,321 @@ pub enum ConfigError {
     TomlSer(#[from] toml::ser::Error),
     #[error("Error deserializing from TOML {0:?}")]
     TomlDe(#[from] toml::de::Error),
+    #[error("Configuration file {0:?} does not exist")]
+    NotFound(PathBuf),
+    #[error("Error deserializing configuration fragment {0:?} from TOML {1:?}")]
+    Fragment(PathBuf, toml::de::Error),
+    #[error("Environment variable {0} does not refer to a valid key")]
+    InvalidOverride(String),
+    #[error("Failed to read secret file {0:?}: {1}")]
+    SecretFile(PathBuf, std::io::Error),
+}
+
+/// The key of a value `serde_ignored` ignored, below `prefix`
+fn ignored_key(prefix: &str, path: &serde_ignored::Path) -> String {
+    // Optional values show up as `?` in the path
+    prefix
+        .split('.')
+        .chain(path.to_string().split('.'))
+        .filter(|x| !x.is_empty() && *x != "?")
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The unknown keys in the pipeline entries and DNS backends.
+/// These are internally tagged enums, which serde buffers before deserializing them,
+/// so `serde_ignored` does not see their keys. Each table is deserialized as its variant instead
+fn tagged_unknown_keys(value: &toml::Value) -> Vec<String> {
+    let mut keys = Vec::new();
+
+    let pipeline = value.get("pipeline").and_then(|x| x.as_array());
+    for (index, entry) in pipeline.into_iter().flatten().enumerate() {
+        let prefix = format!("pipeline.{}", index);
+        match entry.get("service").and_then(|x| x.as_str()) {
+            Some("webhook") => {
+                variant_unknown_keys::<WebhookConfig>(entry, "service", &prefix, &mut keys)
+            }
+            Some("command") => {
+                variant_unknown_keys::<CommandConfig>(entry, "service", &prefix, &mut keys)
+            }
+            _ => variant_unknown_keys::<UnitVariant>(entry, "service", &prefix, &mut keys),
+        }
+    }
+
+    let dns = value.get("dns");
+    let zones = dns
+        .and_then(|dns| dns.get("zones"))
+        .and_then(|x| x.as_array());
+    let backends = dns
+        .and_then(|dns| dns.get("backend"))
+        .map(|backend| ("dns.backend".to_string(), backend))
+        .into_iter()
+        .chain(
+            zones
+                .into_iter()
+                .flatten()
+                .enumerate()
+                .filter_map(|(index, zone)| {
+                    zone.get("backend")
+                        .map(|backend| (format!("dns.zones.{}.backend", index), backend))
+                }),
+        );
+    for (prefix, backend) in backends {
+        match backend.get("type").and_then(|x| x.as_str()) {
+            Some("hosts") => {
+                variant_unknown_keys::<HostsBackendConfig>(backend, "type", &prefix, &mut keys)
+            }
+            Some("zonefile") => {
+                variant_unknown_keys::<ZonefileBackendConfig>(backend, "type", &prefix, &mut keys)
+            }
+            Some("powerdns") => {
+                variant_unknown_keys::<PowerDnsBackendConfig>(backend, "type", &prefix, &mut keys)
+            }
+            _ => variant_unknown_keys::<UnitVariant>(backend, "type", &prefix, &mut keys),
+        }
+    }
+
+    keys
+}
+
+/// A variant without fields, such as `PipelineEntry::Dns`
+#[derive(Deserialize)]
+struct UnitVariant {}
+
+/// Add the keys of `table` that are not part of the variant `T` to `keys`. `tag` is the key naming the variant.
+/// Unit variants use `UnitVariant`, all of their keys except the tag are unknown.
+/// Invalid values are ignored here, they are reported when the configuration is deserialized
+fn variant_unknown_keys<T: serde::de::DeserializeOwned>(
+    table: &toml::Value,
+    tag: &str,
+    prefix: &str,
+    keys: &mut Vec<String>,
+) {
+    let mut table = match table.as_table() {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    table.remove(tag);
+
+    let _ = serde_ignored::deserialize::<_, _, T>(toml::Value::Table(table), |path| {
+        keys.push(ignored_key(prefix, &path));
+    });
+}
+
+/// Environment variables starting with this prefix override configuration keys,
+/// e.g. `ORDIN_DNS__SERVER` overrides `server` in `[dns]`
+const ENV_PREFIX: &str = "ORDIN_";
+
+/// Keys whose value may be read from the file named by `<key>_file` instead
+const SECRET_KEYS: [&str; 3] = ["secret", "admin_token", "api_key"];
+
+/// Add the default pipeline to the configuration if it does not configure one.
+/// Otherwise a pipeline in a fragment would be appended to an empty pipeline, dropping the default services
+fn seed_pipeline(value: &mut toml::Value) -> Result<(), ConfigError> {
+    if let toml::Value::Table(table) = value {
+        if !table.contains_key("pipeline") {
+            table.insert(
+                "pipeline".to_string(),
+                toml::Value::try_from(default_pipeline())?,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge a configuration fragment into `base`.
+/// Tables are merged recursively, arrays are appended to, and other values are replaced
+fn merge(base: &mut toml::Value, fragment: toml::Value) {
+    match (base, fragment) {
+        (toml::Value::Table(base), toml::Value::Table(fragment)) => {
+            for (key, value) in fragment {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base), toml::Value::Array(fragment)) => base.extend(fragment),
+        (base, fragment) => *base = fragment,
+    }
+}
+
+/// Set the key named by the environment variable `name`.
+/// Path segments are separated by `__`, and index arrays if they are numeric
+fn apply_env_override(root: &mut toml::Value, name: &str, value: &str) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::InvalidOverride(name.to_string());
+    let path = name[ENV_PREFIX.len()..]
+        .split("__")
+        .map(|x| x.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if path.iter().any(String::is_empty) {
+        return Err(invalid());
+    }
+
+    debug!(
+        "Overriding configuration key {} from the environment",
+        path.join(".")
+    );
+    let (last, parents) = path.split_last().ok_or_else(invalid)?;
+    let mut current = root;
+    for segment in parents {
+        current = match current {
+            toml::Value::Table(table) => table
+                .entry(segment.as_str())
+                .or_insert_with(|| toml::Value::Table(Default::default())),
+            toml::Value::Array(array) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index))
+                .ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        };
+    }
+
+    match current {
+        toml::Value::Table(table) => {
+            let value = match SECRET_KEYS.contains(&last.as_str()) {
+                true => toml::Value::String(value.to_string()),
+                false => override_value(table.get(last.as_str()), value),
+            };
+            table.insert(last.to_string(), value);
+        }
+        toml::Value::Array(array) => {
+            let slot = last
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index))
+                .ok_or_else(invalid)?;
+            *slot = override_value(Some(slot), value);
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+/// Values replacing strings stay strings, anything else is parsed as a TOML value if possible
+fn override_value(existing: Option<&toml::Value>, value: &str) -> toml::Value {
+    match existing {
+        Some(toml::Value::String(_)) => toml::Value::String(value.to_string()),
+        _ => toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(value.to_string())),
+    }
+}
+
+/// Replace `<key>_file` with the contents of the file it names, for every key in `SECRET_KEYS`
+fn read_secret_files(value: &mut toml::Value) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::Table(table) => {
+            for key in SECRET_KEYS {
+                let file_key = format!("{}_file", key);
+                if let Some(toml::Value::String(path)) = table.get(&file_key).cloned() {
+                    table.remove(&file_key);
+                    trace!("Reading {} from {:?}", key, &path);
+                    let secret = fs::read_to_string(&path)
+                        .map_err(|e| ConfigError::SecretFile(PathBuf::from(&path), e))?;
+                    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+                    table.insert(key.to_string(), toml::Value::String(secret));
+                }
+            }
+            table
+                .iter_mut()
+                .try_for_each(|(_, value)| read_secret_files(value))
+        }
+        toml::Value::Array(array) => array.iter_mut().try_for_each(read_secret_files),
+        _ => Ok(()),
+    }
 }
 
 impl Config {
-    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+    /// Read the configuration, creating a default if it does not exist. See `read` for `dry_run`
+    pub fn from_file(path: &Path, dry_run: bool) -> Result<Self, ConfigError> {
         debug!("Reading configuration from file");
         if !path.exists() {
             trace!(
                 "Configuration file does not yet exist, writing default to {:?}",
                 path
             );
-            return Self::create_default(path);
+            Self::create_default(path)?;
+        }
+
+        let (this, unknown_keys) = Self::read(path, dry_run)?;
+        for key in unknown_keys {
+            warn!("Unknown configuration key {}", key);
+        }
+        Ok(this)
+    }
+
+    /// The files in the `conf.d` directory next to the configuration file, in the order they are merged
+    pub fn fragments(path: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+        let dir = Self::fragment_dir(path);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut fragments = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|x| x.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        fragments
+            .retain(|x| x.is_file() && x.extension().map(|ext| ext == "toml").unwrap_or(false));
+        fragments.sort();
+        Ok(fragments)
+    }
+
+    /// The directory configuration fragments are read from, `conf.d` next to the configuration file
+    pub fn fragment_dir(path: &Path) -> PathBuf {
+        path.parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join("conf.d")
+    }
+
+    /// Read the configuration without creating a default if it does not exist.
+    /// Fragments in `conf.d` are merged into it, then environment variable overrides and secret files are applied.
+    /// `dry_run` enables `global.dry_run` regardless of the configuration, for the `--dry-run` flag.
+    /// Returns the configuration and the keys which are not part of the configuration format
+    pub fn read(path: &Path, dry_run: bool) -> Result<(Self, Vec<String>), ConfigError> {
+        if !path.exists() {
+            return Err(ConfigError::NotFound(path.to_path_buf()));
         }
 
         trace!("Reading configuration from {:?}", path);
         let mut f = fs::File::open(path)?;
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf)?;
+        let mut buf = String::new();
+        f.read_to_string(&mut buf)?;
+        let mut value = toml::from_str::<toml::Value>(&buf)?;
+        seed_pipeline(&mut value)?;
+
+        for fragment in Self::fragments(path)? {
+            trace!("Reading configuration fragment {:?}", &fragment);
+            let contents = fs::read_to_string(&fragment)?;
+            let fragment_value = toml::from_str::<toml::Value>(&contents)
+                .map_err(|e| ConfigError::Fragment(fragment.clone(), e))?;
+            merge(&mut value, fragment_value);
+        }
+
+        let mut overrides = env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect::<Vec<_>>();
+        overrides.sort();
+        for (name, override_value) in overrides {
+            apply_env_override(&mut value, &name, &override_value)?;
+        }
+        read_secret_files(&mut value)?;
 
         trace!("Deserializing configuration file");
-        let this = toml::from_slice(buf.as_slice())?;
-        Ok(this)
+        let mut unknown_keys = tagged_unknown_keys(&value);
+        let mut this: Self = serde_ignored::deserialize(value, |path| {
+            unknown_keys.push(ignored_key("", &path));
+        })?;
+        this.global.dry_run |= dry_run;
+        Ok((this, unknown_keys))
     }
 
-    fn create_default(path: &Path) -> Result<Self, ConfigError> {
+    fn create_default(path: &Path) -> Result<(), ConfigError> {
         if let Some(parent) = path.parent() {
             trace!(
                 "Configuration path has a parent, creating directories at {:?}",
@@ -98,6 +802,203 @@ impl Config {
             env!("CARGO_PKG_NAME")
         );
 
-        Ok(default)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(toml: &str) -> toml::Value {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn merges_fragments() {
+        let mut base = value(
+            r#"
+            [global]
+            domain = "example.com"
+            port = 8080
+            [ansible]
+            playbooks = ["a.yml"]
+            "#,
+        );
+        merge(
+            &mut base,
+            value(
+                r#"
+                [global]
+                port = 9090
+                [ansible]
+                playbooks = ["b.yml"]
+                [registry]
+                path = "registry.json"
+                "#,
+            ),
+        );
+
+        assert_eq!(
+            base,
+            value(
+                r#"
+                [global]
+                domain = "example.com"
+                port = 9090
+                [ansible]
+                playbooks = ["a.yml", "b.yml"]
+                [registry]
+                path = "registry.json"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn appends_fragment_pipeline_to_default_pipeline() {
+        let mut base = value("[global]\ndomain = \"example.com\"");
+        seed_pipeline(&mut base).unwrap();
+        merge(
+            &mut base,
+            value("[[pipeline]]\nservice = \"webhook\"\nurl = \"http://127.0.0.1/\""),
+        );
+
+        let config = base.try_into::<Config>().unwrap();
+        assert!(matches!(
+            config.pipeline[..],
+            [
+                PipelineEntry::Dns,
+                PipelineEntry::Ansible,
+                PipelineEntry::Webhook(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn keeps_configured_pipeline() {
+        let mut base = value("[[pipeline]]\nservice = \"dns\"");
+        seed_pipeline(&mut base).unwrap();
+        assert_eq!(base, value("[[pipeline]]\nservice = \"dns\""));
+    }
+
+    #[test]
+    fn overrides_from_environment() {
+        let mut root = value(
+            r#"
+            [global]
+            port = 8080
+            domain = "example.com"
+            [[dns.zones]]
+            zone_name = "example.com"
+            ttl = 3600
+            "#,
+        );
+        apply_env_override(&mut root, "ORDIN_GLOBAL__PORT", "9090").unwrap();
+        apply_env_override(&mut root, "ORDIN_GLOBAL__DOMAIN", "123").unwrap();
+        apply_env_override(&mut root, "ORDIN_DNS__ZONES__0__TTL", "60").unwrap();
+        apply_env_override(&mut root, "ORDIN_AUTH__SECRET", "true").unwrap();
+        apply_env_override(&mut root, "ORDIN_GLOBAL__ALLOW", "['10.0.0.0/8']").unwrap();
+
+        assert_eq!(
+            root,
+            value(
+                r#"
+                [global]
+                port = 9090
+                domain = "123"
+                allow = ["10.0.0.0/8"]
+                [[dns.zones]]
+                zone_name = "example.com"
+                ttl = 60
+                [auth]
+                secret = "true"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_environment_overrides() {
+        let mut root = value("[global]\nport = 8080\n[[dns.zones]]\nzone_name = \"example.com\"");
+        for name in [
+            "ORDIN_GLOBAL____PORT",
+            "ORDIN_GLOBAL__PORT__X",
+            "ORDIN_DNS__ZONES__1__TTL",
+            "ORDIN_DNS__ZONES__X__TTL",
+        ] {
+            assert!(matches!(
+                apply_env_override(&mut root, name, "1"),
+                Err(ConfigError::InvalidOverride(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reads_secret_files() {
+        let path = env::temp_dir().join(format!("ordin-test-secret-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+
+        let mut root = value(&format!(
+            "[auth]\nsecret_file = {:?}\n[[dns.zones]]\nzone_name = \"example.com\"\n[dns.zones.backend]\ntype = \"powerdns\"\napi_key_file = {:?}",
+            path, path
+        ));
+        let result = read_secret_files(&mut root);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(root["auth"]["secret"].as_str(), Some("s3cret"));
+        assert!(root["auth"].get("secret_file").is_none());
+        assert_eq!(
+            root["dns"]["zones"][0]["backend"]["api_key"].as_str(),
+            Some("s3cret")
+        );
+    }
+
+    #[test]
+    fn reports_missing_secret_files() {
+        let mut root = value("[auth]\nsecret_file = \"/nonexistent/ordin-secret\"");
+        assert!(matches!(
+            read_secret_files(&mut root),
+            Err(ConfigError::SecretFile(..))
+        ));
+    }
+
+    #[test]
+    fn finds_unknown_keys_in_tagged_tables() {
+        let value = toml::from_str::<toml::Value>(
+            r#"
+            [dns]
+            backend = { type = "nsupdate", bogus = 1 }
+            [[dns.zones]]
+            zone_name = "example.com"
+            backend = { type = "hosts", path = "/etc/hosts", bogus = 1 }
+            [[pipeline]]
+            service = "dns"
+            bogus = 1
+            [[pipeline]]
+            service = "webhook"
+            url = "http://127.0.0.1/"
+            headers = { Authorization = "Bearer token" }
+            bogus = 1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            tagged_unknown_keys(&value),
+            [
+                "pipeline.0.bogus",
+                "pipeline.1.bogus",
+                "dns.backend.bogus",
+                "dns.zones.0.backend.bogus"
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_known_keys_in_tagged_tables() {
+        let value = toml::Value::try_from(Config::default()).unwrap();
+        assert!(tagged_unknown_keys(&value).is_empty());
     }
 }
@@ -1,3 +1,5 @@
+use crate::services::jobs::ApprovalError;
+use crate::services::registry::RegistryError;
 use actix_web::http::StatusCode;
 use actix_web::ResponseError;
 use thiserror::Error;
@@ -6,16 +8,63 @@ pub type ServiceResult<T> = Result<T, ServiceError>;
 
 #[derive(Debug, Error)]
 pub enum ServiceError {
-    #[error("DNS error")]
+    #[error("DNS error: {0}")]
     Dns(#[from] crate::services::dns::DnsError),
-    #[error("Ansible error")]
+    #[error("Ansible error: {0}")]
     Ansible(#[from] crate::services::ansible::AnsibleError),
+    #[error("Webhook error: {0}")]
+    Webhook(#[from] crate::services::webhook::WebhookError),
+    #[error("Command error: {0}")]
+    Command(#[from] crate::services::command::CommandError),
+    #[error("Invalid hostname: {0}")]
+    Name(#[from] crate::services::naming::NameError),
+    #[error("Invalid address: {0}")]
+    Address(#[from] std::net::AddrParseError),
+    #[error("Invalid request: {0}")]
+    Payload(actix_web::Error),
+    #[error("The address of the sender is unknown")]
+    UnknownSender,
+    #[error("Unauthorized: {0}")]
+    Auth(#[from] crate::services::auth::AuthError),
+    #[error("{0}")]
+    Registry(#[from] RegistryError),
+    #[error("{0}")]
+    Approval(#[from] ApprovalError),
+    #[error("{0}")]
+    Throttle(#[from] crate::services::throttle::ThrottleError),
+    #[error("Forbidden: {0}")]
+    Access(#[from] crate::services::access::AccessError),
+}
+
+impl ServiceError {
+    /// The outcome of a failed request, as used in metrics
+    pub fn outcome(&self) -> &'static str {
+        match self.status_code() {
+            StatusCode::BAD_REQUEST => "invalid",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::CONFLICT => "conflict",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+            _ => "error",
+        }
+    }
 }
 
 impl ResponseError for ServiceError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::Dns(_) | Self::Ansible(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Dns(_) | Self::Ansible(_) | Self::Webhook(_) | Self::Command(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Self::Payload(e) => e.as_response_error().status_code(),
+            Self::Name(_) | Self::Address(_) | Self::UnknownSender => StatusCode::BAD_REQUEST,
+            Self::Auth(_) => StatusCode::UNAUTHORIZED,
+            Self::Access(_) => StatusCode::FORBIDDEN,
+            Self::Registry(RegistryError::Conflict(_)) => StatusCode::CONFLICT,
+            Self::Registry(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Approval(ApprovalError::Unauthorized) => StatusCode::UNAUTHORIZED,
+            Self::Approval(_) => StatusCode::NOT_FOUND,
+            Self::Throttle(_) => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
@@ -0,0 +1,57 @@
+use crate::appdata::WebData;
+use actix_web::{web, HttpResponse};
+use log::warn;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    service: &'static str,
+    /// The reason the service is not ready, if any
+    error: Option<String>,
+}
+
+/// Whether the process is alive
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Whether every service in the pipeline is able to run
+pub async fn readyz(data: WebData) -> HttpResponse {
+    let checks = web::block(move || -> Result<_, ()> {
+        Ok(data
+            .pipeline
+            .check_steps()
+            .into_iter()
+            .map(|(service, result)| Check {
+                service,
+                error: result.err().map(|e| e.to_string()),
+            })
+            .collect::<Vec<_>>())
+    })
+    .await;
+    let checks = match checks {
+        Ok(x) => x,
+        Err(_) => return HttpResponse::ServiceUnavailable().finish(),
+    };
+
+    let ready = checks.iter().all(|check| check.error.is_none());
+    if !ready {
+        checks
+            .iter()
+            .filter_map(|check| check.error.as_ref().map(|e| (check.service, e)))
+            .for_each(|(service, e)| warn!("Service {} is not ready: {}", service, e));
+    }
+
+    let body = Readiness { ready, checks };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
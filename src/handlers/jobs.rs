@@ -0,0 +1,45 @@
+use crate::appdata::WebData;
+use crate::error::ServiceResult;
+use crate::handlers::{Admin, Empty};
+use crate::services::jobs::{ApprovalError, PendingJobInfo};
+use crate::services::registry::{Claim, RegistryError};
+use actix_web::web;
+use log::{info, warn};
+
+/// List the jobs awaiting approval
+pub async fn list(_: Admin, data: WebData) -> ServiceResult<web::Json<Vec<PendingJobInfo>>> {
+    let pending = data.pending.as_ref().ok_or(ApprovalError::Disabled)?;
+    Ok(web::Json(pending.list()))
+}
+
+/// Approve a pending job, running its pipeline
+pub async fn approve(_: Admin, data: WebData, id: web::Path<u64>) -> ServiceResult<Empty> {
+    let pending = data.pending.as_ref().ok_or(ApprovalError::Disabled)?;
+    let job = pending.take(*id)?;
+    let _context = job.context.enter();
+    info!("Job {} for {} was approved", id, job.target.fqdn);
+
+    // The name may have been claimed by another machine while the job was pending
+    if let (Some(registry), Some(registration)) = (&data.registry, job.registration) {
+        match registry.claim(&job.target.fqdn, registration, job.take_over)? {
+            Claim::Registered => {}
+            Claim::NeedsApproval(reason) => {
+                warn!("Job {} can't take over its name: {}", id, reason);
+                return Err(RegistryError::Conflict(job.target.fqdn).into());
+            }
+        }
+    }
+
+    data.throttle.start(&job.target);
+    data.spawn_job(job.target, job.context);
+    Ok(Empty)
+}
+
+/// Reject a pending job
+pub async fn reject(_: Admin, data: WebData, id: web::Path<u64>) -> ServiceResult<Empty> {
+    let pending = data.pending.as_ref().ok_or(ApprovalError::Disabled)?;
+    let job = pending.take(*id)?;
+    let _context = job.context.enter();
+    info!("Job {} for {} was rejected", id, job.target.fqdn);
+    Ok(Empty)
+}
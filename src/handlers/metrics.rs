@@ -0,0 +1,14 @@
+use crate::appdata::WebData;
+use crate::metrics;
+use actix_web::HttpResponse;
+
+/// Prometheus metrics
+pub async fn metrics(data: WebData) -> HttpResponse {
+    if let Some(pending) = &data.pending {
+        metrics::JOBS_PENDING.set(pending.len() as i64);
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
@@ -1,7 +1,18 @@
-use crate::util::Ready;
+use crate::appdata::WebData;
+use crate::config::ForwardedHeader;
+use crate::error::ServiceError;
+use crate::services::jobs::ApprovalError;
+use crate::util::{constant_time_eq, Ready};
 use actix_web::dev::Payload;
-use actix_web::{FromRequest, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Responder};
+use log::warn;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
+pub mod health;
+pub mod jobs;
+pub mod metrics;
 pub mod phone_home;
 
 /// Empty response
@@ -19,25 +30,233 @@ impl Responder for Empty {
 /// The sender of the request
 pub struct Sender {
     /// The real IP address of the sender
-    pub ip: String,
+    pub ip: IpAddr,
 }
 
 impl FromRequest for Sender {
-    type Error = ();
-    type Future = Ready<Result<Self, ()>>;
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, ServiceError>>;
     type Config = ();
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let conn_info = req.connection_info();
-        let ip = match conn_info.realip_remote_addr() {
-            Some(x) => x.to_string(),
-            None => return Ready::new(Err(())),
+        let peer = match req.peer_addr() {
+            Some(x) => x.ip().to_canonical(),
+            None => return Ready::new(Err(ServiceError::UnknownSender)),
+        };
+
+        let data = match WebData::current(req) {
+            Some(x) => x,
+            None => return Ready::new(Ok(Self { ip: peer })),
         };
 
-        let ip = ip.replace('[', "").replace(']', "");
-        let mut ip_parts = ip.split(':').collect::<Vec<_>>();
-        ip_parts.pop();
-        let ip = ip_parts.join(":");
+        let ip = if data.proxies.is_trusted(&peer) {
+            match data
+                .proxies
+                .client_ip(peer, &forwarded_addresses(req, data.proxies.header()))
+            {
+                Some(x) => x.to_canonical(),
+                None => {
+                    warn!(
+                        "Rejected request from proxy {}: invalid forwarded address",
+                        peer
+                    );
+                    return Ready::new(Err(ServiceError::UnknownSender));
+                }
+            }
+        } else {
+            peer
+        };
+
+        if let Err(e) = data.access.check(ip) {
+            warn!("Rejected request from {}: {}", ip, e);
+            return Ready::new(Err(e.into()));
+        }
+
         Ready::new(Ok(Self { ip }))
     }
 }
+
+/// The addresses in the forwarding `header`, in the order in which they were added.
+/// Addresses that cannot be parsed, such as `unknown`, are `None`
+fn forwarded_addresses(req: &HttpRequest, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        let mut lines = req.headers().get_all(name).collect::<Vec<_>>();
+        // actix-http's header map places the second line of a repeated header before the first
+        if lines.len() > 1 {
+            lines.swap(0, 1);
+        }
+
+        lines
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+
+    match header {
+        ForwardedHeader::XForwardedFor => values("x-forwarded-for")
+            .map(parse_forwarded_address)
+            .collect(),
+        ForwardedHeader::Forwarded => values("forwarded")
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_forwarded_address(value))
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Parse an address from a forwarding header, which may be quoted and may include a port,
+/// e.g. `192.0.2.1`, `192.0.2.1:4711` or `"[2001:db8::1]:4711"`
+fn parse_forwarded_address(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|x| x.ip()))
+        .or_else(|_| {
+            value
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
+
+/// Proof that the request carries a valid token, if authentication is configured.
+/// The token is taken from the `{token}` path segment or the `token` query parameter
+pub struct Authorized;
+
+impl FromRequest for Authorized {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, ServiceError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let data = WebData::current(req);
+        let auth = match data.as_ref().and_then(|data| data.auth.as_ref()) {
+            Some(x) => x,
+            None => return Ready::new(Ok(Self)),
+        };
+
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+        let token = req.match_info().get("token").or_else(|| {
+            query
+                .as_ref()
+                .and_then(|q| q.get("token"))
+                .map(String::as_str)
+        });
+
+        let result = auth.verify(token).map(|_| Self).map_err(|e| {
+            warn!("Rejected request from {:?}: {}", req.peer_addr(), e);
+            e.into()
+        });
+        Ready::new(result)
+    }
+}
+
+/// Proof that the request carries the admin token in the `Authorization: Bearer` header
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, ServiceError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let data = WebData::current(req);
+        let pending = match data.as_ref().and_then(|data| data.pending.as_ref()) {
+            Some(x) => x,
+            None => return Ready::new(Err(ApprovalError::Disabled.into())),
+        };
+
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let result = match token {
+            Some(token) if constant_time_eq(token.as_bytes(), pending.admin_token().as_bytes()) => {
+                Ok(Self)
+            }
+            _ => {
+                warn!("Rejected admin request from {:?}", req.peer_addr());
+                Err(ApprovalError::Unauthorized.into())
+            }
+        };
+        Ready::new(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn parses_forwarded_addresses() {
+        let parse = |value| parse_forwarded_address(value);
+        assert_eq!(parse("192.0.2.1"), "192.0.2.1".parse().ok());
+        assert_eq!(parse(" 192.0.2.1:4711"), "192.0.2.1".parse().ok());
+        assert_eq!(parse("2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(parse("\"[2001:db8::1]:4711\""), "2001:db8::1".parse().ok());
+        assert_eq!(parse("[2001:db8::1]"), "2001:db8::1".parse().ok());
+        assert_eq!(parse("unknown"), None);
+        assert_eq!(parse("_hidden"), None);
+    }
+
+    #[test]
+    fn reads_only_the_configured_header() {
+        // A client's own Forwarded header is passed on unchanged by proxies appending to X-Forwarded-For
+        let req = TestRequest::default()
+            .header("Forwarded", "for=192.0.2.66")
+            .header("X-Forwarded-For", "198.51.100.7")
+            .to_http_request();
+
+        assert_eq!(
+            forwarded_addresses(&req, ForwardedHeader::XForwardedFor),
+            vec!["198.51.100.7".parse().ok()]
+        );
+        assert_eq!(
+            forwarded_addresses(&req, ForwardedHeader::Forwarded),
+            vec!["192.0.2.66".parse().ok()]
+        );
+    }
+
+    #[test]
+    fn reads_forwarded_chains() {
+        let req = TestRequest::default()
+            .header(
+                "Forwarded",
+                "for=192.0.2.66;proto=https, for=\"[2001:db8::1]:4711\"",
+            )
+            .header("Forwarded", "for=unknown;by=10.0.0.1")
+            .to_http_request();
+
+        assert_eq!(
+            forwarded_addresses(&req, ForwardedHeader::Forwarded),
+            vec!["192.0.2.66".parse().ok(), "2001:db8::1".parse().ok(), None]
+        );
+    }
+
+    #[test]
+    fn keeps_order_of_repeated_headers() {
+        let req = TestRequest::default()
+            .header("X-Forwarded-For", "192.0.2.66")
+            .header("X-Forwarded-For", "198.51.100.7, 10.0.0.3")
+            .header("X-Forwarded-For", "10.0.0.2")
+            .to_http_request();
+
+        assert_eq!(
+            forwarded_addresses(&req, ForwardedHeader::XForwardedFor),
+            vec![
+                "192.0.2.66".parse().ok(),
+                "198.51.100.7".parse().ok(),
+                "10.0.0.3".parse().ok(),
+                "10.0.0.2".parse().ok()
+            ]
+        );
+    }
+}
@@ -1,32 +1,166 @@
 use crate::appdata::WebData;
-use crate::error::ServiceResult;
-use crate::handlers::{Empty, Sender};
-use crate::services::{Service, Target};
+use crate::error::{ServiceError, ServiceResult};
+use crate::handlers::{Authorized, Empty, Sender};
+use crate::logging::JobContext;
+use crate::metrics;
+use crate::services::registry::{Claim, Registration, TakeOver};
+use crate::services::throttle::Admission;
 use actix_web::web;
+use log::info;
 use serde::Deserialize;
-use std::thread;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[derive(Deserialize)]
 pub struct Request {
     hostname: String,
+    /// The FQDN of the machine, if cloud-init is configured to post it
+    fqdn: Option<String>,
+    /// The cloud-init instance ID of the machine, used to recognize it in the registry
+    instance_id: Option<String>,
+    /// Additional addresses of the machine
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+/// Options passed in the query string of the phone-home URL
+#[derive(Deserialize)]
+pub struct Options {
+    /// Only report the changes the pipeline would make, see `global.dry_run`
+    #[serde(default)]
+    dry_run: bool,
+    /// Take the name over from the machine it is registered to, with `registry.on_conflict = 'override'`
+    #[serde(default, rename = "override")]
+    take_over: bool,
+}
+
+/// What happened to a phone-home
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    /// The pipeline was started
+    Started,
+    /// The job awaits approval
+    Pending,
+    /// A job for the machine is running or recently succeeded
+    Ignored,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Pending => "pending",
+            Self::Ignored => "ignored",
+        }
+    }
 }
 
 pub async fn phone_home(
+    sender: Result<Sender, ServiceError>,
+    authorized: Result<Authorized, ServiceError>,
     data: WebData,
-    payload: web::Form<Request>,
-    sender: Sender,
+    options: Result<web::Query<Options>, actix_web::Error>,
+    payload: Result<web::Form<Request>, actix_web::Error>,
 ) -> ServiceResult<Empty> {
-    thread::Builder::new()
-        .name(format!("phone-home-{}-{}", &payload.hostname, &sender.ip))
-        .spawn(move || {
-            let target = Target::new(&sender.ip, &payload.hostname);
-            let dns = data.dns.clone();
-            let ansible = data.ansible.clone();
-
-            dns.run(&target).expect("Running DNS service");
-            ansible.run(&target).expect("Running Ansible service");
-        })
-        .expect("Spawning thread");
-
-    Ok(Empty)
+    let outcome = sender.and_then(|sender| {
+        authorized?;
+        let options = options.map_err(ServiceError::Payload)?;
+        let payload = payload.map_err(ServiceError::Payload)?;
+        handle(&data, sender, &options, &payload)
+    });
+
+    let label = match &outcome {
+        Ok(outcome) => outcome.label(),
+        Err(e) => e.outcome(),
+    };
+    metrics::PHONE_HOME_REQUESTS
+        .with_label_values(&[label])
+        .inc();
+
+    outcome.map(|_| Empty)
+}
+
+fn handle(
+    data: &WebData,
+    sender: Sender,
+    options: &Options,
+    payload: &Request,
+) -> ServiceResult<Outcome> {
+    let context = JobContext::new(&payload.hostname, sender.ip);
+    let _context = context.enter();
+
+    let posted = [payload.ipv4.map(IpAddr::V4), payload.ipv6.map(IpAddr::V6)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let posted = data.addresses.check_posted(&posted, &data.access)?;
+    let addresses = data.addresses.resolve(sender.ip, &posted);
+
+    let mut target = data.naming.target(
+        &sender.ip.to_string(),
+        addresses,
+        &payload.hostname,
+        payload.fqdn.as_deref(),
+    )?;
+    target.dry_run = data.dry_run || options.dry_run;
+
+    // Jobs awaiting approval must not own their name yet, it is only registered once the job is approved
+    let requires_approval = data
+        .pending
+        .as_ref()
+        .map(|pending| pending.requires_approval(&target))
+        .unwrap_or(false);
+
+    // Without [auth], anyone could ask to take a name over
+    let take_over = (options.take_over && data.auth.is_some()).then_some(TakeOver::Requested);
+    let registration = Registration::new(payload.instance_id.as_deref(), sender.ip);
+    let conflict = match &data.registry {
+        Some(registry) => {
+            let claim = if target.dry_run || requires_approval {
+                registry.check(&target.fqdn, registration.clone(), take_over)?
+            } else {
+                registry.claim(&target.fqdn, registration.clone(), take_over)?
+            };
+            match claim {
+                Claim::Registered => None,
+                Claim::NeedsApproval(reason) => Some(reason),
+            }
+        }
+        None => None,
+    };
+    // A dry run does not register the name, not even once it is approved
+    let registration = data
+        .registry
+        .as_ref()
+        .and(Some(registration))
+        .filter(|_| !target.dry_run);
+
+    // A conflict can only require approval if approval is configured
+    if let Some(pending) = &data.pending {
+        if let Some(reason) = conflict {
+            // Approving the job approves taking the name over
+            let take_over = Some(TakeOver::Approved);
+            pending.add(context, target, registration, take_over, reason);
+            return Ok(Outcome::Pending);
+        }
+
+        if requires_approval {
+            let reason = "New machine".to_string();
+            pending.add(context, target, registration, take_over, reason);
+            return Ok(Outcome::Pending);
+        }
+    }
+
+    match data.throttle.admit(&target)? {
+        Admission::Admitted => {
+            data.spawn_job(target, context);
+            Ok(Outcome::Started)
+        }
+        admission => {
+            info!(
+                "Ignoring phone-home of {} ({}): {:?}",
+                target.fqdn, target.ip, admission
+            );
+            Ok(Outcome::Ignored)
+        }
+    }
 }
@@ -0,0 +1,143 @@
+use actix_web::dev::ServiceRequest;
+use log::LevelFilter;
+use serde_json::json;
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_JOB: RefCell<Option<JobContext>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown log format {:?}, expected 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+/// Identifies the phone-home a log line was emitted for
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub id: u64,
+    pub hostname: String,
+    pub ip: String,
+}
+
+impl JobContext {
+    /// Create the context of a new job
+    pub fn new<S: AsRef<str>, I: ToString>(hostname: S, ip: I) -> Self {
+        Self {
+            id: NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed),
+            hostname: hostname.as_ref().to_string(),
+            ip: ip.to_string(),
+        }
+    }
+
+    /// Attach this context to log lines emitted by the current thread, until the guard is dropped
+    pub fn enter(&self) -> ContextGuard {
+        let previous = CURRENT_JOB.with(|current| current.replace(Some(self.clone())));
+        ContextGuard(previous)
+    }
+}
+
+/// Restores the previous job context when dropped
+pub struct ContextGuard(Option<JobContext>);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_JOB.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Initialize the logger. `verbose` is the number of times the verbose flag was given
+pub fn init(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
+
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+
+            CURRENT_JOB.with(|current| {
+                if let Some(job) = current.borrow().as_ref() {
+                    line["job_id"] = json!(job.id);
+                    line["hostname"] = json!(job.hostname);
+                    line["ip"] = json!(job.ip);
+                }
+            });
+
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    builder.init();
+}
+
+/// Replaces tokens in the access log
+const REDACTED: &str = "REDACTED";
+
+/// The format of access log lines. Like actix-web's default format, but the request line
+/// is replaced by `%{request}xi`, see `redacted_request`
+pub const ACCESS_LOG_FORMAT: &str = r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+/// The request line with phone-home tokens redacted, both the `phone-home/{token}` path segment and the `token` query parameter
+pub fn redacted_request(req: &ServiceRequest) -> String {
+    let mut path = Vec::new();
+    let mut after_phone_home = false;
+    for segment in req.path().split('/') {
+        path.push(if after_phone_home && !segment.is_empty() {
+            REDACTED
+        } else {
+            segment
+        });
+        after_phone_home = segment == "phone-home";
+    }
+    let mut line = format!("{} {}", req.method(), path.join("/"));
+
+    if !req.query_string().is_empty() {
+        let query = req
+            .query_string()
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some(("token", _)) => format!("token={}", REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>();
+        line.push('?');
+        line.push_str(&query.join("&"));
+    }
+
+    format!("{} {:?}", line, req.version())
+}
@@ -1,38 +1,34 @@
-use crate::appdata::ApplicationData;
+use crate::appdata::{ApplicationData, CurrentData};
 use crate::config::Config;
-use crate::opts::Opts;
-use crate::services::ansible::AnsibleService;
-use crate::services::dns::DnsService;
+use crate::opts::{Command, Opts};
 use actix_web::middleware::normalize::TrailingSlash;
 use actix_web::middleware::{Logger, NormalizePath};
 use actix_web::{web, App, HttpServer};
-use log::{error, info, LevelFilter};
+use log::{error, info, warn};
+use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
 
 mod appdata;
+mod cli;
 mod config;
 mod error;
 mod handlers;
+mod logging;
+mod metrics;
 mod opts;
+mod reload;
 mod services;
+#[cfg(test)]
+mod testing;
+mod tls;
 mod util;
+mod validate;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let opts = Opts::new();
-    match opts.verbose {
-        0 => env_logger::builder()
-            .filter_level(LevelFilter::Error)
-            .init(),
-        1 => env_logger::builder().filter_level(LevelFilter::Warn).init(),
-        2 => env_logger::builder().filter_level(LevelFilter::Info).init(),
-        3 => env_logger::builder()
-            .filter_level(LevelFilter::Debug)
-            .init(),
-        _ => env_logger::builder()
-            .filter_level(LevelFilter::Trace)
-            .init(),
-    }
+    logging::init(opts.verbose, opts.log_format);
 
     info!(
         "Starting {} v{}",
@@ -40,7 +36,31 @@ async fn main() -> std::io::Result<()> {
         env!("CARGO_PKG_VERSION")
     );
 
-    let config = match Config::from_file(&opts.config) {
+    if let Some(Command::CheckConfig) = &opts.command {
+        let problems = check_config(&opts.config, opts.dry_run);
+        if !problems.is_empty() {
+            for problem in problems {
+                println!("{}", problem);
+            }
+            exit(1);
+        }
+
+        println!("{:?} is valid", &opts.config);
+        return Ok(());
+    }
+
+    if opts.strict {
+        let problems = check_config(&opts.config, opts.dry_run);
+        if !problems.is_empty() {
+            error!("The configuration has {} problem(s):", problems.len());
+            for problem in problems {
+                error!("{}", problem);
+            }
+            exit(1);
+        }
+    }
+
+    let config = match Config::from_file(&opts.config, opts.dry_run) {
         Ok(x) => x,
         Err(e) => {
             error!("Failed to load config: {:?}", e);
@@ -48,27 +68,121 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    let bind_addr = if config.global.ipv6 {
-        format!("[::]:{}", config.global.port)
-    } else {
-        format!("0.0.0.0:{}", config.global.port)
+    let result = match &opts.command {
+        Some(Command::Jobs { url, command }) => Some(cli::jobs(&config, url.as_deref(), command)),
+        Some(Command::Provision { hostname, ip, fqdn }) => {
+            Some(cli::provision(&config, hostname, *ip, fqdn.as_deref()))
+        }
+        Some(Command::Inventory { command }) => Some(cli::inventory(&config, command)),
+        Some(Command::Dns { command }) => Some(cli::dns(&config, command)),
+        Some(Command::Serve) | Some(Command::CheckConfig) | None => None,
     };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            error!("{}", e);
+            exit(1);
+        }
+
+        return Ok(());
+    }
 
-    let ansible_service = AnsibleService::new(&config).expect("Creating Ansible service");
-    let dns_service = DnsService::new(&config);
-    let appdata = ApplicationData::new(config, ansible_service, dns_service);
+    if config.global.dry_run {
+        warn!("Dry run is enabled, DNS records and the Ansible inventory are not changed");
+    }
+
+    if config.global.ipv6 {
+        warn!("global.ipv6 is deprecated, Ordin listens on both IPv4 and IPv6 unless configured otherwise with global.listen");
+    }
+
+    if config.auth.is_none() {
+        warn!("No [auth] section is configured, phone-home requests are not authenticated");
+    }
 
-    HttpServer::new(move || {
+    let appdata = match ApplicationData::new(&config) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    };
+
+    let current = web::Data::new(CurrentData::new(appdata));
+    let reloader = reload::Reloader::new(&opts, config.clone(), current.clone());
+    reload::watch(Arc::new(reloader))?;
+
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(
+                Logger::new(logging::ACCESS_LOG_FORMAT)
+                    .custom_request_replace("request", logging::redacted_request),
+            )
             .wrap(NormalizePath::new(TrailingSlash::Trim))
-            .data(appdata.clone())
+            .app_data(current.clone())
             .route(
                 "phone-home",
                 web::post().to(handlers::phone_home::phone_home),
             )
-    })
-    .bind(&bind_addr)?
-    .run()
-    .await
+            .route(
+                "phone-home/{token}",
+                web::post().to(handlers::phone_home::phone_home),
+            )
+            .route("healthz", web::get().to(handlers::health::healthz))
+            .route("readyz", web::get().to(handlers::health::readyz))
+            .route("metrics", web::get().to(handlers::metrics::metrics))
+            .route("jobs", web::get().to(handlers::jobs::list))
+            .route("jobs/{id}/approve", web::post().to(handlers::jobs::approve))
+            .route("jobs/{id}/reject", web::post().to(handlers::jobs::reject))
+    });
+
+    let tls_config = match &config.global.tls {
+        Some(tls_config) => match tls::server_config(tls_config) {
+            Ok((server_config, resolver)) => {
+                tls::reload_on_sighup(resolver)?;
+                Some(server_config)
+            }
+            Err(e) => {
+                error!("Failed to load TLS configuration: {}", e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    for addr in config.global.listen_addresses() {
+        let listener = match util::bind_listener(addr) {
+            Ok(x) => x,
+            // Without global.listen, both address families are tried, and one may be unavailable
+            Err(e) if config.global.listen.is_empty() && util::is_unsupported_family(&e) => {
+                warn!(
+                    "Not listening on {}, the address family is not supported: {}",
+                    addr, e
+                );
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        server = match &tls_config {
+            Some(tls_config) => {
+                info!("Listening on {} (HTTPS)", addr);
+                server.listen_rustls(listener, tls_config.clone())?
+            }
+            None => {
+                info!("Listening on {}", addr);
+                server.listen(listener)?
+            }
+        };
+    }
+
+    server.run().await
+}
+
+/// Read and validate the configuration, without creating a default if it does not exist
+fn check_config(path: &Path, dry_run: bool) -> Vec<String> {
+    match Config::read(path, dry_run) {
+        Ok((config, unknown_keys)) => validate::validate(&config, &unknown_keys)
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        Err(e) => vec![e.to_string()],
+    }
 }
@@ -0,0 +1,53 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    /// Phone-home requests, by outcome
+    pub static ref PHONE_HOME_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "ordin_phone_home_requests_total",
+        "Phone-home requests by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    /// Duration of DNS updates, by zone and result
+    pub static ref DNS_UPDATE_DURATION: HistogramVec = register_histogram_vec!(
+        "ordin_dns_update_duration_seconds",
+        "Duration of DNS updates",
+        &["zone", "result"]
+    )
+    .unwrap();
+    /// Duration of Ansible playbook runs, by playbook and result
+    pub static ref PLAYBOOK_DURATION: HistogramVec = register_histogram_vec!(
+        "ordin_playbook_duration_seconds",
+        "Duration of Ansible playbook runs",
+        &["playbook", "result"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0]
+    )
+    .unwrap();
+    /// Jobs whose pipeline is running
+    pub static ref JOBS_IN_FLIGHT: IntGauge =
+        register_int_gauge!("ordin_jobs_in_flight", "Jobs whose pipeline is running").unwrap();
+    /// Jobs awaiting approval
+    pub static ref JOBS_PENDING: IntGauge =
+        register_int_gauge!("ordin_jobs_pending", "Jobs awaiting approval").unwrap();
+}
+
+/// The `result` label for the outcome of an operation
+pub fn result_label<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// Render all registered metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("Encoding metrics");
+    String::from_utf8(buf).expect("Metrics are valid UTF-8")
+}
@@ -1,3 +1,5 @@
+use crate::logging::LogFormat;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -6,6 +8,11 @@ pub struct Opts {
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// The format of log lines, 'text' or 'json'.
+    /// JSON lines emitted while processing a phone-home carry its job ID, hostname and sender IP
+    #[structopt(long, default_value = "text")]
+    pub log_format: LogFormat,
+
     #[structopt(
         short,
         long,
@@ -13,6 +20,94 @@ pub struct Opts {
         default_value = "/etc/ordin/config.toml"
     )]
     pub config: PathBuf,
+
+    /// Refuse to start if the configuration file is missing or has problems, see `check-config`
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Only report the changes DNS and Ansible would make, instead of making them.
+    /// Overrides `global.dry_run`
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Run the server. This is the default if no command is given
+    Serve,
+    /// Check the configuration, listing all problems found
+    CheckConfig,
+    /// Run the pipeline for a machine once, as if it phoned home
+    Provision {
+        hostname: String,
+        /// The address the machine would phone home from
+        ip: IpAddr,
+        /// The FQDN of the machine, if it should not be derived from the hostname
+        #[structopt(long)]
+        fqdn: Option<String>,
+    },
+    /// Manage the hosts in the Ansible inventory
+    Inventory {
+        #[structopt(subcommand)]
+        command: InventoryCommand,
+    },
+    /// Manage DNS records
+    Dns {
+        #[structopt(subcommand)]
+        command: DnsCommand,
+    },
+    /// Manage jobs awaiting approval on a running Ordin server
+    Jobs {
+        /// The URL of the Ordin server. Defaults to the address and port in the configuration
+        #[structopt(long)]
+        url: Option<String>,
+
+        #[structopt(subcommand)]
+        command: JobsCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum JobsCommand {
+    /// List the jobs awaiting approval
+    List,
+    /// Approve a job, running its pipeline
+    Approve { id: u64 },
+    /// Reject a job
+    Reject { id: u64 },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum InventoryCommand {
+    /// List the hosts in the inventory and their groups
+    List,
+    /// Add a host to the cloud-init group
+    Add { name: String },
+    /// Remove a host from all groups
+    Remove { name: String },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DnsCommand {
+    /// Create the records of a machine, replacing existing records
+    Add {
+        hostname: String,
+        /// The address of the machine. Additional addresses are derived from `global.address_mappings`
+        ip: IpAddr,
+        /// The FQDN of the machine, if it should not be derived from the hostname
+        #[structopt(long)]
+        fqdn: Option<String>,
+    },
+    /// Remove the A and AAAA records of a name
+    Remove {
+        fqdn: String,
+        /// The zone to remove the records from. Defaults to the most specific zone containing the name
+        #[structopt(long)]
+        zone: Option<String>,
+    },
 }
 
 impl Opts {
@@ -0,0 +1,157 @@
+use crate::appdata::{AppDataError, CurrentData};
+use crate::config::{Config, ConfigError};
+use crate::opts::Opts;
+use crate::validate::{self, Problem};
+use actix_web::web;
+use log::{debug, error, info, warn};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// How often the configuration is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error("Failed to load config: {0}")]
+    Config(#[from] ConfigError),
+    #[error("The configuration has {} problem(s): {}", .0.len(), join(.0))]
+    Invalid(Vec<Problem>),
+    #[error("{0}")]
+    AppData(#[from] AppDataError),
+}
+
+fn join(problems: &[Problem]) -> String {
+    problems
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+struct State {
+    /// The configuration currently in use
+    config: Config,
+    /// When the configuration was last modified, as of the last reload
+    modified: Option<SystemTime>,
+}
+
+/// Reloads the configuration, replacing the application data used for new requests
+pub struct Reloader {
+    path: PathBuf,
+    /// Whether `--dry-run` was given, which applies to every reloaded configuration
+    dry_run: bool,
+    /// Whether `--strict` was given. Like at startup, only then do all problems prevent a reload
+    strict: bool,
+    current: web::Data<CurrentData>,
+    state: Mutex<State>,
+}
+
+impl Reloader {
+    pub fn new(opts: &Opts, config: Config, current: web::Data<CurrentData>) -> Self {
+        Self {
+            path: opts.config.clone(),
+            dry_run: opts.dry_run,
+            strict: opts.strict,
+            current,
+            state: Mutex::new(State {
+                config,
+                modified: modified(&opts.config),
+            }),
+        }
+    }
+
+    /// Reload the configuration. If it is invalid, the current configuration is kept
+    pub fn reload(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.modified = modified(&self.path);
+
+        match self.load(&state.config) {
+            Ok(config) => {
+                info!("Reloaded configuration from {:?}", &self.path);
+                state.config = config;
+            }
+            Err(e) => error!(
+                "Failed to reload configuration, keeping the current configuration: {}",
+                e
+            ),
+        }
+    }
+
+    /// Reload the configuration if it was modified since the last reload
+    fn reload_if_modified(&self) {
+        let changed = {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            modified(&self.path) != state.modified
+        };
+
+        if changed {
+            debug!("Configuration in {:?} was modified", &self.path);
+            self.reload();
+        }
+    }
+
+    fn load(&self, previous: &Config) -> Result<Config, ReloadError> {
+        let (config, unknown_keys) = Config::read(&self.path, self.dry_run)?;
+        if self.strict {
+            let problems = validate::validate(&config, &unknown_keys);
+            if !problems.is_empty() {
+                return Err(ReloadError::Invalid(problems));
+            }
+        } else {
+            // As at startup, only problems that prevent creating the services are fatal
+            for key in unknown_keys {
+                warn!("Unknown configuration key {}", key);
+            }
+        }
+
+        let data = self.current.get().reload(&config)?;
+        self.current.replace(data);
+
+        if config.global.listen_addresses() != previous.global.listen_addresses()
+            || config.global.tls != previous.global.tls
+        {
+            warn!("Changes to global.listen, global.port and global.tls only take effect after a restart");
+        }
+        Ok(config)
+    }
+}
+
+/// Reload the configuration on SIGHUP and when the configuration changes
+pub fn watch(reloader: Arc<Reloader>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    let on_signal = reloader.clone();
+    thread::Builder::new()
+        .name("config-reload".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                on_signal.reload();
+            }
+        })?;
+
+    thread::Builder::new()
+        .name("config-watch".to_string())
+        .spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            reloader.reload_if_modified();
+        })?;
+
+    Ok(())
+}
+
+/// When the configuration file or its fragments were last modified.
+/// Adding or removing a fragment modifies the fragment directory
+fn modified(path: &Path) -> Option<SystemTime> {
+    let fragments = Config::fragments(path).unwrap_or_default();
+    [path.to_path_buf(), Config::fragment_dir(path)]
+        .iter()
+        .chain(fragments.iter())
+        .filter_map(|x| fs::metadata(x).and_then(|x| x.modified()).ok())
+        .max()
+}
@@ -0,0 +1,50 @@
+use crate::config::GlobalConfig;
+use ipnet::IpNet;
+use log::trace;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AccessError {
+    #[error("{0} is in a denied network")]
+    Denied(IpAddr),
+    #[error("{0} is not in an allowed network")]
+    NotAllowed(IpAddr),
+}
+
+/// Determines which addresses may phone home
+#[derive(Debug, Clone)]
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn new(config: &GlobalConfig) -> Self {
+        Self {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        }
+    }
+
+    /// Check whether `ip` may phone home.
+    /// Denied networks take precedence over allowed networks. If no allowed networks are configured, all addresses not denied are allowed
+    pub fn check(&self, ip: IpAddr) -> Result<(), AccessError> {
+        if let Some(net) = self.deny.iter().find(|net| net.contains(&ip)) {
+            trace!("{} is denied by {}", ip, net);
+            return Err(AccessError::Denied(ip));
+        }
+
+        if self.allow.is_empty() {
+            return Ok(());
+        }
+
+        match self.allow.iter().find(|net| net.contains(&ip)) {
+            Some(net) => {
+                trace!("{} is allowed by {}", ip, net);
+                Ok(())
+            }
+            None => Err(AccessError::NotAllowed(ip)),
+        }
+    }
+}
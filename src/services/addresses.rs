@@ -0,0 +1,89 @@
+use crate::config::{AddressMapping, GlobalConfig};
+use crate::services::access::{AccessError, AccessList};
+use log::{trace, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Determines all addresses of a machine
+#[derive(Debug, Clone)]
+pub struct Addresses {
+    mappings: Vec<AddressMapping>,
+    accept_posted: bool,
+}
+
+impl Addresses {
+    pub fn new(config: &GlobalConfig) -> Self {
+        Self {
+            mappings: config.address_mappings.clone(),
+            accept_posted: config.accept_posted_addresses,
+        }
+    }
+
+    /// The posted addresses which may be registered for a machine.
+    /// They are ignored unless `global.accept_posted_addresses` is set, and must be allowed by `access`
+    pub fn check_posted(
+        &self,
+        posted: &[IpAddr],
+        access: &AccessList,
+    ) -> Result<Vec<IpAddr>, AccessError> {
+        if !self.accept_posted {
+            if !posted.is_empty() {
+                warn!(
+                    "Ignoring posted addresses {:?}, global.accept_posted_addresses is not set",
+                    posted
+                );
+            }
+            return Ok(Vec::new());
+        }
+
+        posted.iter().try_for_each(|ip| access.check(*ip))?;
+        Ok(posted.to_vec())
+    }
+
+    /// All addresses of a machine: the address it phoned home from, the addresses it posted,
+    /// and the addresses mapped from those by the configured address mappings
+    pub fn resolve(&self, sender: IpAddr, posted: &[IpAddr]) -> Vec<IpAddr> {
+        let mut addresses = Vec::new();
+        for ip in std::iter::once(sender).chain(posted.iter().copied()) {
+            if !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+
+        let mapped = addresses
+            .iter()
+            .flat_map(|ip| {
+                self.mappings
+                    .iter()
+                    .filter(move |mapping| mapping.from.contains(ip))
+                    .map(move |mapping| map_address(*ip, mapping))
+            })
+            .collect::<Vec<_>>();
+
+        for ip in mapped {
+            if !addresses.contains(&ip) {
+                trace!("Adding mapped address {}", ip);
+                addresses.push(ip);
+            }
+        }
+
+        addresses
+    }
+}
+
+/// Map `ip` into the `to` network of the mapping, keeping the host part of the address
+fn map_address(ip: IpAddr, mapping: &AddressMapping) -> IpAddr {
+    let host = to_bits(ip) & to_bits(mapping.from.hostmask());
+    let bits = to_bits(mapping.to.network()) | (host & to_bits(mapping.to.hostmask()));
+
+    match mapping.to.network() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+fn to_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
@@ -1,12 +1,14 @@
-use crate::services::{Service, Target};
-use crate::Config;
-use log::{debug, trace, warn};
-use std::collections::HashMap;
+use crate::config::AnsibleConfig;
+use crate::metrics;
+use crate::services::{PlayLog, Service, Target};
+use crate::util::{check_writable, file_lock, find_executable};
+use log::{debug, info, trace, warn};
 use std::ffi::OsStr;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -17,6 +19,10 @@ pub enum AnsibleError {
     AnsibleFailed,
     #[error("Failed to (de)serialize YAML {0:?}")]
     Yaml(#[from] serde_yaml::Error),
+    #[error("Ansible playbook {0:?} does not exist")]
+    MissingPlaybook(PathBuf),
+    #[error("Program {0:?} was not found")]
+    NotFound(PathBuf),
 }
 
 #[derive(Debug, Clone)]
@@ -24,9 +30,9 @@ pub struct AnsibleService {
     playbooks: Vec<Playbook>,
     inventory: Inventory,
     binary: Option<PathBuf>,
-    domain: String,
-    play_logdir: PathBuf,
-    play_log: bool,
+    play_log: PlayLog,
+    /// Serializes updates to the inventory between concurrent jobs, also across reloads
+    lock: Arc<Mutex<()>>,
 }
 
 #[derive(Debug, Clone)]
@@ -36,43 +42,31 @@ pub struct Inventory(PathBuf);
 pub struct Playbook(PathBuf);
 
 impl AnsibleService {
-    pub fn new(config: &Config) -> Result<Self, AnsibleError> {
+    pub fn new(config: &AnsibleConfig) -> Result<Self, AnsibleError> {
         let playbooks = config
-            .ansible
             .playbooks
             .iter()
-            .map(|x| {
+            .inspect(|x| {
                 if !x.exists() {
                     warn!("Ansible playbook {:?} does not exist", x);
                 }
-                x
             })
-            .filter(|x| x.exists())
             .map(|x| Playbook(x.clone()))
             .collect::<Vec<_>>();
 
-        if !config.ansible.inventory.exists() {
+        if !config.inventory.exists() {
             warn!(
                 "Inventory {:?} does not exist (It will be created later though)",
-                &config.ansible.inventory
+                &config.inventory
             );
         }
 
-        if !config.ansible.play_logdir.exists() {
-            trace!(
-                "Ansible play logging directory {:?} does not exist, creating",
-                config.ansible.play_logdir
-            );
-            fs::create_dir_all(&config.ansible.play_logdir)?;
-        }
-
         Ok(Self {
             playbooks,
-            inventory: Inventory(config.ansible.inventory.clone()),
-            binary: config.ansible.ansible_playbook_binary.clone(),
-            domain: config.global.domain.clone(),
-            play_log: config.ansible.play_logs,
-            play_logdir: config.ansible.play_logdir.clone(),
+            inventory: Inventory(config.inventory.clone()),
+            binary: config.ansible_playbook_binary.clone(),
+            play_log: PlayLog::new(&config.play_logdir, config.play_logs)?,
+            lock: file_lock(&config.inventory),
         })
     }
 }
@@ -130,6 +124,14 @@ mod models {
             Ok(this)
         }
 
+        /// Read the inventory, or an empty one if it does not exist, without creating it
+        pub fn read_existing(path: &Path) -> Result<Self, AnsibleError> {
+            if !path.exists() {
+                return Ok(Self::default());
+            }
+            Self::read(path)
+        }
+
         pub fn write(&self, path: &Path) -> Result<(), AnsibleError> {
             trace!("Opening inventory file at {:?}", path);
             let mut f = fs::File::create(path)?;
@@ -155,12 +157,98 @@ impl Service for AnsibleService {
     fn run(&self, target: &Target) -> Result<(), Self::Err> {
         debug!("Running Ansible service for {:?}", target);
 
+        if target.dry_run {
+            info!("Dry run: {}", self.dry_run(target)?);
+            return Ok(());
+        }
+
         if !self.is_in_inventory(target)? {
             trace!("Target {:?} is not yet in inventory. Adding", target);
             self.add_to_inventory(target)?;
         }
 
+        self.run_playbooks(target, &self.inventory.0, false)?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), Self::Err> {
+        trace!("Checking inventory {:?}", &self.inventory.0);
+        if self.inventory.0.exists() {
+            models::Inventory::read(&self.inventory.0)?;
+        }
+        check_writable(&self.inventory.0)?;
+
+        if let Some(playbook) = self.playbooks.iter().find(|x| !x.0.exists()) {
+            return Err(AnsibleError::MissingPlaybook(playbook.0.clone()));
+        }
+
+        let binary = self.binary();
+        find_executable(binary).ok_or_else(|| AnsibleError::NotFound(binary.to_path_buf()))?;
+        Ok(())
+    }
+}
+
+impl AnsibleService {
+    fn binary(&self) -> &Path {
+        self.binary
+            .as_deref()
+            .unwrap_or_else(|| Path::new("ansible-playbook"))
+    }
+
+    /// Describe the change to the inventory without making it, and run the playbooks
+    /// with `--check --diff` against a copy of the inventory containing the target.
+    /// Returns the change and the output of the playbooks
+    pub fn dry_run(&self, target: &Target) -> Result<String, AnsibleError> {
+        let name = self.format_target_name(target);
+        let mut inventory = models::Inventory::read_existing(&self.inventory.0)?;
+
+        let known = inventory
+            .all
+            .children
+            .values()
+            .any(|child| child.hosts.contains_key(&name));
+        let mut report = if known {
+            format!("{} is already in inventory {:?}", name, &self.inventory.0)
+        } else {
+            inventory
+                .all
+                .children
+                .entry("cloud-init".to_string())
+                .or_default()
+                .hosts
+                .insert(name.clone(), None);
+            format!("not adding {} to inventory {:?}", name, &self.inventory.0)
+        };
+
+        // The copy is placed next to the inventory, so group_vars and host_vars still apply
+        let copy = self
+            .inventory
+            .0
+            .with_extension(format!("ordin-dry-run-{}.yml", name));
+        inventory.write(&copy)?;
+        let result = self.run_playbooks(target, &copy, true);
+        fs::remove_file(&copy)?;
+
+        for (playbook, stdout) in self.playbooks.iter().zip(result?) {
+            report.push_str(&format!(
+                "\nansible-playbook --check --diff {:?} for {}:\n{}",
+                &playbook.0,
+                name,
+                stdout.trim_end()
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Run the playbooks for `target`, with `--check --diff` if `check` is set. Returns the output of each playbook
+    fn run_playbooks(
+        &self,
+        target: &Target,
+        inventory: &Path,
+        check: bool,
+    ) -> Result<Vec<String>, AnsibleError> {
         let formatted_hostname = self.format_target_name(target);
+        let mut outputs = Vec::new();
         for (index, playbook) in self.playbooks.iter().enumerate() {
             trace!(
                 "Running Ansible playbook {}/{} for {}",
@@ -168,38 +256,53 @@ impl Service for AnsibleService {
                 self.playbooks.len(),
                 &formatted_hostname
             );
-            self.run_playbook(target, playbook)?;
+            let started = Instant::now();
+            let result = self.run_playbook(target, playbook, inventory, check);
+            if !check {
+                metrics::PLAYBOOK_DURATION
+                    .with_label_values(&[
+                        &playbook.0.to_string_lossy(),
+                        metrics::result_label(&result),
+                    ])
+                    .observe(started.elapsed().as_secs_f64());
+            }
+            outputs.push(result?);
         }
 
-        Ok(())
+        Ok(outputs)
     }
-}
 
-impl AnsibleService {
-    fn run_playbook(&self, target: &Target, playbook: &Playbook) -> Result<(), AnsibleError> {
+    fn run_playbook(
+        &self,
+        target: &Target,
+        playbook: &Playbook,
+        inventory: &Path,
+        check: bool,
+    ) -> Result<String, AnsibleError> {
         trace!("Spawning ansible-playbook child process for {:?}", target);
 
         if !playbook.0.exists() {
             warn!("Ansible playbook {:?} does not exist", &playbook.0);
-            return Ok(());
+            return Ok(format!("Playbook {:?} does not exist", &playbook.0));
+        }
+
+        let mut command = Command::new(self.binary());
+        command.args([
+            OsStr::new("-i"),
+            inventory.as_os_str(),
+            OsStr::new("-l"),
+            OsStr::new(&self.format_target_name(target)),
+        ]);
+        if check {
+            command.args(["--check", "--diff"]);
         }
 
-        let child = Command::new(
-            self.binary
-                .as_deref()
-                .unwrap_or(&PathBuf::from("ansible-playbook")),
-        )
-        .args(&[
-            &OsStr::new("-i"),
-            &self.inventory.0.as_os_str(),
-            &OsStr::new("-l"),
-            &OsStr::new(&self.format_target_name(target)),
-            &playbook.0.as_os_str(),
-        ])
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+        let child = command
+            .arg(&playbook.0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
 
         trace!("Waiting for ansible-playbook to complete");
         let output = child.wait_with_output()?;
@@ -207,70 +310,99 @@ impl AnsibleService {
         let stdout = String::from_utf8_lossy(&output.stdout);
         let stderr = String::from_utf8_lossy(&output.stderr);
 
-        if self.play_log {
-            let path = self.play_logdir.join(format!(
-                "{}-ansible_playbook_{}_{}-{}.log",
-                time::OffsetDateTime::now_utc().unix_timestamp(),
-                playbook.0.file_name().unwrap_or(OsStr::new("")).to_string_lossy(),
-                target.ip,
-                target.hostname
-            ));
-            trace!(
-                "Ansible playbook logging is enabled. Logging to {:?}",
-                &path
-            );
-            let mut f = fs::File::create(path)?;
-
-            f.write_all(b"STDOUT:\n")?;
-            f.write_all(stdout.as_bytes())?;
-            f.write_all(b"\nSTDERR:\n")?;
-            f.write_all(stderr.as_bytes())?;
-        }
+        self.play_log.write(
+            "ansible_playbook",
+            &playbook
+                .0
+                .file_name()
+                .unwrap_or(OsStr::new(""))
+                .to_string_lossy(),
+            target,
+            &stdout,
+            &stderr,
+        )?;
 
         trace!("Ansible stdout: {}", &stdout);
         trace!("Ansible stderr: {}", &stderr);
 
         if !output.status.success() {
+            if check {
+                warn!(
+                    "ansible-playbook --check --diff {:?} failed:\n{}",
+                    &playbook.0,
+                    stdout.trim_end()
+                );
+            }
             return Err(AnsibleError::AnsibleFailed);
         }
 
         trace!("Ansible-playbook completed successfully");
 
-        Ok(())
+        Ok(stdout.into_owned())
     }
 
     fn is_in_inventory(&self, target: &Target) -> Result<bool, AnsibleError> {
         trace!("Checking if target {:?} is in inventory", target);
-        let inventory = models::Inventory::read(&self.inventory.0)?;
-        let children = inventory
-            .all
-            .children
-            .iter()
-            .any(|(_, child)| child.hosts.contains_key(&self.format_target_name(target)));
-        Ok(children)
+        let name = self.format_target_name(target);
+        Ok(self.hosts()?.iter().any(|(_, host)| host == &name))
     }
 
     fn format_target_name(&self, target: &Target) -> String {
-        format!("{}.{}", &target.hostname, &self.domain)
+        target.fqdn.to_string()
     }
 
     fn add_to_inventory(&self, target: &Target) -> Result<(), AnsibleError> {
+        self.add_host(&self.format_target_name(target))
+    }
+
+    /// The hosts in the inventory and the groups they are in
+    pub fn hosts(&self) -> Result<Vec<(String, String)>, AnsibleError> {
+        let inventory = models::Inventory::read(&self.inventory.0)?;
+        let mut hosts = inventory
+            .all
+            .children
+            .into_iter()
+            .flat_map(|(group, child)| {
+                child
+                    .hosts
+                    .into_keys()
+                    .map(move |host| (group.clone(), host))
+            })
+            .collect::<Vec<_>>();
+        hosts.sort();
+        Ok(hosts)
+    }
+
+    /// Add a host to the cloud-init group of the inventory
+    pub fn add_host(&self, name: &str) -> Result<(), AnsibleError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
         let mut inventory = models::Inventory::read(&self.inventory.0)?;
 
         inventory
             .all
             .children
             .entry("cloud-init".to_string())
-            .and_modify(|child| {
-                child.hosts.insert(self.format_target_name(target), None);
-            })
-            .or_insert_with(|| {
-                let mut hosts = HashMap::new();
-                hosts.insert(self.format_target_name(target), None);
-                models::Child { hosts }
-            });
+            .or_default()
+            .hosts
+            .insert(name.to_string(), None);
 
         inventory.write(&self.inventory.0)?;
         Ok(())
     }
+
+    /// Remove a host from all groups of the inventory. Returns whether the host was in the inventory
+    pub fn remove_host(&self, name: &str) -> Result<bool, AnsibleError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut inventory = models::Inventory::read(&self.inventory.0)?;
+
+        let mut removed = false;
+        for child in inventory.all.children.values_mut() {
+            removed |= child.hosts.remove(name).is_some();
+        }
+
+        if removed {
+            inventory.write(&self.inventory.0)?;
+        }
+        Ok(removed)
+    }
 }
@@ -0,0 +1,75 @@
+use crate::config::AuthConfig;
+use crate::util::constant_time_eq;
+use hmac::{Hmac, Mac};
+use log::trace;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("No token was provided")]
+    Missing,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Malformed signed token")]
+    Malformed,
+    #[error("Token expired at {0}")]
+    Expired(u64),
+}
+
+/// Authenticates phone-home requests using a shared secret.
+///
+/// A token is either the secret itself, or, in signed mode, `<expires>.<signature>`,
+/// where `expires` is a UNIX timestamp and `signature` the hex encoded HMAC-SHA256 of `expires` keyed with the secret
+#[derive(Debug, Clone)]
+pub struct Auth {
+    secret: String,
+    signed: bool,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            secret: config.secret.clone(),
+            signed: config.signed,
+        }
+    }
+
+    /// Verify the token presented by a request
+    pub fn verify(&self, token: Option<&str>) -> Result<(), AuthError> {
+        let token = token.ok_or(AuthError::Missing)?;
+        if self.signed {
+            self.verify_signed(token)
+        } else if constant_time_eq(token.as_bytes(), self.secret.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidToken)
+        }
+    }
+
+    fn verify_signed(&self, token: &str) -> Result<(), AuthError> {
+        let (expires, signature) = token.split_once('.').ok_or(AuthError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| AuthError::Malformed)?;
+
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(expires.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let expires = expires.parse::<u64>().map_err(|_| AuthError::Malformed)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        if now > expires {
+            return Err(AuthError::Expired(expires));
+        }
+
+        trace!("Signed token is valid until {}", expires);
+        Ok(())
+    }
+}
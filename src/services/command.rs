@@ -0,0 +1,130 @@
+use crate::config::{AnsibleConfig, CommandConfig};
+use crate::services::{PlayLog, Service, Target};
+use crate::util::{find_executable, render_template};
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("IO error {0:?}")]
+    Io(#[from] std::io::Error),
+    #[error("Command {0:?} exited with {1}")]
+    Failed(PathBuf, std::process::ExitStatus),
+    #[error("Program {0:?} was not found")]
+    NotFound(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandService {
+    command: PathBuf,
+    args: Vec<String>,
+    env: HashMap<String, String>,
+    workdir: Option<PathBuf>,
+    play_log: PlayLog,
+}
+
+impl CommandService {
+    /// Create the service. The output of the command is logged to the Ansible play-log directory,
+    /// if the Ansible service is configured and play logging is enabled
+    pub fn new(
+        config: &CommandConfig,
+        ansible: Option<&AnsibleConfig>,
+    ) -> Result<Self, CommandError> {
+        let play_log = match ansible {
+            Some(ansible) => PlayLog::new(&ansible.play_logdir, ansible.play_logs)?,
+            None => PlayLog::new(&PathBuf::new(), false)?,
+        };
+
+        Ok(Self {
+            command: config.command.clone(),
+            args: config.args.clone(),
+            env: config.env.clone(),
+            workdir: config.workdir.clone(),
+            play_log,
+        })
+    }
+}
+
+impl Service for CommandService {
+    type Err = CommandError;
+
+    fn run(&self, target: &Target) -> Result<(), Self::Err> {
+        debug!("Running command {:?} for {:?}", &self.command, target);
+
+        let vars = [
+            ("hostname", target.hostname.as_str()),
+            ("ip", target.ip.as_str()),
+            ("fqdn", target.fqdn.as_str()),
+        ];
+
+        let mut command = Command::new(&self.command);
+        command
+            .args(self.args.iter().map(|x| render_template(x, &vars)))
+            .env("ORDIN_HOSTNAME", &target.hostname)
+            .env("ORDIN_IP", &target.ip)
+            .env("ORDIN_FQDN", target.fqdn.as_str())
+            .env(
+                "ORDIN_ADDRESSES",
+                target
+                    .addresses
+                    .iter()
+                    .map(|ip| ip.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+            .envs(self.env.iter().map(|(k, v)| (k, render_template(v, &vars))))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if let Some(workdir) = &self.workdir {
+            command.current_dir(workdir);
+        }
+
+        trace!("Spawning command {:?}", &command);
+        let output = command.spawn()?.wait_with_output()?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        self.play_log.write(
+            "command",
+            &self
+                .command
+                .file_name()
+                .unwrap_or(OsStr::new(""))
+                .to_string_lossy(),
+            target,
+            &stdout,
+            &stderr,
+        )?;
+
+        trace!("Command stdout: {}", &stdout);
+        trace!("Command stderr: {}", &stderr);
+
+        if !output.status.success() {
+            return Err(CommandError::Failed(self.command.clone(), output.status));
+        }
+
+        trace!("Command completed successfully");
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), Self::Err> {
+        let program = match &self.workdir {
+            Some(workdir)
+                if self.command.is_relative() && self.command.components().count() > 1 =>
+            {
+                workdir.join(&self.command)
+            }
+            _ => self.command.clone(),
+        };
+
+        find_executable(&program).ok_or_else(|| CommandError::NotFound(self.command.clone()))?;
+        Ok(())
+    }
+}
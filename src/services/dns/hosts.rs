@@ -0,0 +1,189 @@
+use crate::config::HostsBackendConfig;
+use crate::services::dns::{
+    diff, record_type, run_reload_command, DnsBackend, DnsError, Record, RECORD_TYPES,
+};
+use crate::services::naming::DomainName;
+use crate::util::{check_writable, file_lock, tmp_path};
+use log::{debug, trace};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const BEGIN_MARKER: &str = "# BEGIN ORDIN MANAGED BLOCK";
+const END_MARKER: &str = "# END ORDIN MANAGED BLOCK";
+
+/// Creates records by maintaining a managed block in a hosts-style file,
+/// such as `/etc/hosts` or a dnsmasq `addn-hosts` file.
+/// Lines outside of the managed block are left untouched
+#[derive(Debug)]
+pub struct HostsBackend {
+    path: PathBuf,
+    reload_command: Option<Vec<String>>,
+    reload_signal: Option<(String, PathBuf)>,
+    /// Serializes updates to the file between concurrent jobs, also across reloads and zones sharing the file
+    lock: Arc<Mutex<()>>,
+}
+
+impl HostsBackend {
+    pub fn new(config: &HostsBackendConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            reload_command: config.reload_command.clone(),
+            reload_signal: config.reload_signal.clone().zip(config.pid_file.clone()),
+            lock: file_lock(&config.path),
+        }
+    }
+
+    fn reload(&self) -> Result<(), DnsError> {
+        if let Some((signal, pid_file)) = &self.reload_signal {
+            let pid = fs::read_to_string(pid_file)?;
+            let pid = pid.trim();
+            if pid.is_empty() {
+                return Err(DnsError::InvalidReload("PID file is empty"));
+            }
+
+            debug!("Sending SIG{} to process {}", signal, pid);
+            run_reload_command(&[
+                "kill".to_string(),
+                "-s".to_string(),
+                signal.clone(),
+                pid.to_string(),
+            ])?;
+        }
+
+        if let Some(command) = &self.reload_command {
+            debug!("Running reload command {:?}", command);
+            run_reload_command(command)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DnsBackend for HostsBackend {
+    fn add_record(&self, record: &Record) -> Result<(), DnsError> {
+        self.update(|managed| add_entries(managed, record))
+    }
+
+    fn remove_record(&self, fqdn: &DomainName) -> Result<(), DnsError> {
+        self.update(|managed| remove_entries(managed, fqdn))
+    }
+
+    fn describe_add(&self, record: &Record) -> Result<String, DnsError> {
+        let contents = self.read()?;
+        Ok(diff(
+            &contents,
+            &render(&contents, |managed| add_entries(managed, record)),
+        ))
+    }
+
+    fn describe_remove(&self, fqdn: &DomainName) -> Result<String, DnsError> {
+        let contents = self.read()?;
+        Ok(diff(
+            &contents,
+            &render(&contents, |managed| remove_entries(managed, fqdn)),
+        ))
+    }
+
+    fn check(&self) -> Result<(), DnsError> {
+        check_writable(&self.path)?;
+        Ok(())
+    }
+}
+
+impl HostsBackend {
+    fn read(&self) -> Result<String, DnsError> {
+        trace!("Reading hosts file {:?}", &self.path);
+        if self.path.exists() {
+            Ok(fs::read_to_string(&self.path)?)
+        } else {
+            Ok(String::new())
+        }
+    }
+
+    /// Update the lines of the managed block, write the file and reload
+    fn update<F: FnOnce(&mut Vec<String>)>(&self, update: F) -> Result<(), DnsError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let contents = self.read()?;
+        let output = render(&contents, update);
+
+        let tmp = tmp_path(&self.path);
+        trace!("Writing hosts file to {:?}", &tmp);
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(output.as_bytes())?;
+        f.sync_all()?;
+
+        trace!("Moving {:?} to {:?}", &tmp, &self.path);
+        fs::rename(&tmp, &self.path)?;
+
+        self.reload()
+    }
+}
+
+/// The contents of the file with the lines of the managed block updated
+fn render<F: FnOnce(&mut Vec<String>)>(contents: &str, update: F) -> String {
+    let (before, mut managed, after) = split_managed_block(contents);
+    update(&mut managed);
+
+    let mut output = before;
+    output.push(BEGIN_MARKER.to_string());
+    output.extend(managed);
+    output.push(END_MARKER.to_string());
+    output.extend(after);
+
+    let mut output = output.join("\n");
+    output.push('\n');
+    output
+}
+
+/// Replace the entries of the record's name and address families by entries for the record
+fn add_entries(managed: &mut Vec<String>, record: &Record) {
+    managed.retain(|line| !is_entry(line, &record.fqdn, &record.record_types()));
+    managed.extend(
+        record
+            .addresses
+            .iter()
+            .map(|ip| format!("{} {} {}", ip, record.fqdn, record.fqdn.first_label())),
+    );
+}
+
+fn remove_entries(managed: &mut Vec<String>, fqdn: &DomainName) {
+    managed.retain(|line| !is_entry(line, fqdn, &RECORD_TYPES));
+}
+
+/// Split the file into the lines before, inside and after the managed block.
+/// If there is no managed block, all lines are considered to be before it
+fn split_managed_block(contents: &str) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let mut before = Vec::new();
+    let mut managed = Vec::new();
+    let mut after = Vec::new();
+
+    let mut section = &mut before;
+    let mut seen_block = false;
+    for line in contents.lines() {
+        match line.trim() {
+            BEGIN_MARKER if !seen_block => {
+                seen_block = true;
+                section = &mut managed;
+            }
+            END_MARKER if seen_block => section = &mut after,
+            _ => section.push(line.to_string()),
+        }
+    }
+
+    (before, managed, after)
+}
+
+/// Whether the hosts line is an entry for `fqdn` with an address of one of the record `types`
+fn is_entry(line: &str, fqdn: &DomainName, types: &[&str]) -> bool {
+    let mut parts = line.split_whitespace();
+    let ip = match parts.next().and_then(|x| x.parse::<IpAddr>().ok()) {
+        Some(x) => x,
+        None => return false,
+    };
+
+    types.contains(&record_type(&ip)) && parts.any(|name| name.eq_ignore_ascii_case(fqdn.as_str()))
+}
@@ -0,0 +1,308 @@
+use crate::config::{DnsBackendConfig, DnsConfig, GlobalConfig, ZoneConfig};
+use crate::metrics;
+use crate::services::naming::{DomainName, NameError};
+use crate::services::{Service, Target};
+use log::{debug, info, trace, warn};
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+
+mod hosts;
+mod nsupdate;
+mod powerdns;
+mod zonefile;
+
+pub use hosts::HostsBackend;
+pub use nsupdate::NsupdateBackend;
+pub use powerdns::PowerDnsBackend;
+pub use zonefile::ZonefileBackend;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("IO Error {0:?}")]
+    Io(#[from] std::io::Error),
+    #[error("Nsupdate failed")]
+    NsupdateFailed,
+    #[error("Failed to parse network address {0:?}")]
+    AddrParse(#[from] std::net::AddrParseError),
+    #[error("Reload command {0:?} failed")]
+    ReloadFailed(Vec<String>),
+    #[error("Invalid reload configuration: {0}")]
+    InvalidReload(&'static str),
+    #[error("Invalid domain name {0:?}")]
+    Name(#[from] NameError),
+    #[error("No DNS zone is configured for {0}")]
+    NoZone(String),
+    #[error("DNS zone {0} is configured more than once")]
+    DuplicateZone(String),
+    #[error("Zone file has no SOA record with a valid serial")]
+    NoSoaSerial,
+    #[error("DNS API returned HTTP status {0}: {1}")]
+    ApiStatus(u16, String),
+    #[error("Failed to reach DNS API {0:?}")]
+    ApiTransport(Box<ureq::Transport>),
+    #[error("Program {0:?} was not found")]
+    NotFound(&'static str),
+    #[error("Failed to reach DNS server {0}: {1}")]
+    Unreachable(String, std::io::Error),
+}
+
+/// A DNS record to be created
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// The fully qualified domain name of the machine
+    pub fqdn: DomainName,
+    /// The addresses of the machine. There may be multiple addresses per family
+    pub addresses: Vec<IpAddr>,
+    pub ttl: u64,
+}
+
+impl Record {
+    /// The IPv4 addresses of the record
+    pub fn ipv4(&self) -> impl Iterator<Item = &IpAddr> {
+        self.addresses.iter().filter(|ip| ip.is_ipv4())
+    }
+
+    /// The IPv6 addresses of the record
+    pub fn ipv6(&self) -> impl Iterator<Item = &IpAddr> {
+        self.addresses.iter().filter(|ip| ip.is_ipv6())
+    }
+
+    /// The record types of the record's addresses. Existing records of these types are replaced
+    pub fn record_types(&self) -> Vec<&'static str> {
+        [self.ipv4().next(), self.ipv6().next()]
+            .into_iter()
+            .flatten()
+            .map(record_type)
+            .collect()
+    }
+}
+
+/// The record types created by Ordin
+pub const RECORD_TYPES: [&str; 2] = ["A", "AAAA"];
+
+/// The record type for the address
+pub fn record_type(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+/// The lines of `old` missing from `new` and the lines of `new` missing from `old`,
+/// prefixed with `-` and `+` like a diff
+fn diff(old: &str, new: &str) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    let lines = old
+        .iter()
+        .filter(|line| !new.contains(line))
+        .map(|line| format!("-{}", line))
+        .chain(
+            new.iter()
+                .filter(|line| !old.contains(line))
+                .map(|line| format!("+{}", line)),
+        )
+        .collect::<Vec<_>>();
+
+    if lines.is_empty() {
+        "No changes".to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+/// A way of creating DNS records
+pub trait DnsBackend: Debug + Send + Sync {
+    /// Create the record, replacing any existing records with the same name and an address family of the record
+    fn add_record(&self, record: &Record) -> Result<(), DnsError>;
+
+    /// Remove all records of `RECORD_TYPES` with the name `fqdn`
+    fn remove_record(&self, fqdn: &DomainName) -> Result<(), DnsError>;
+
+    /// The update `add_record` would make, for dry runs
+    fn describe_add(&self, record: &Record) -> Result<String, DnsError>;
+
+    /// The update `remove_record` would make, for dry runs
+    fn describe_remove(&self, fqdn: &DomainName) -> Result<String, DnsError>;
+
+    /// Verify that records can be created, e.g. that the DNS server is reachable
+    fn check(&self) -> Result<(), DnsError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsService {
+    zones: Vec<Zone>,
+}
+
+/// A DNS zone and the backend used to create records in it
+#[derive(Debug, Clone)]
+struct Zone {
+    name: DomainName,
+    ttl: u64,
+    backend: Arc<dyn DnsBackend>,
+}
+
+impl Service for DnsService {
+    type Err = DnsError;
+    fn run(&self, target: &Target) -> Result<(), Self::Err> {
+        if target.dry_run {
+            info!("Dry run: {}", self.dry_run(target)?);
+            return Ok(());
+        }
+
+        let zone = self.target_zone(target)?;
+        let record = Self::record(zone, target);
+        debug!("Adding DNS record {:?} to zone {}", record, &zone.name);
+        let started = Instant::now();
+        let result = zone.backend.add_record(&record);
+        metrics::DNS_UPDATE_DURATION
+            .with_label_values(&[zone.name.as_str(), metrics::result_label(&result)])
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+
+    fn check(&self) -> Result<(), Self::Err> {
+        self.zones.iter().try_for_each(|zone| {
+            trace!("Checking DNS backend of zone {}", &zone.name);
+            zone.backend.check()
+        })
+    }
+}
+
+impl DnsService {
+    /// Describe the record `run` would create for `target`, without creating it
+    pub fn dry_run(&self, target: &Target) -> Result<String, DnsError> {
+        let zone = self.target_zone(target)?;
+        let update = zone.backend.describe_add(&Self::record(zone, target))?;
+        Ok(format!(
+            "not adding DNS record for {} to zone {}:\n{}",
+            target.fqdn, &zone.name, update
+        ))
+    }
+
+    /// Remove the records of `fqdn`. If no zone is given, the record is removed from the
+    /// most specific configured zone `fqdn` is a proper subdomain of
+    pub fn remove(&self, fqdn: &DomainName, zone: Option<&DomainName>) -> Result<(), DnsError> {
+        let zone = self.removal_zone(fqdn, zone)?;
+        debug!("Removing DNS records of {} from zone {}", fqdn, &zone.name);
+        zone.backend.remove_record(fqdn)
+    }
+
+    /// Describe the update `remove` would make, without making it
+    pub fn describe_remove(
+        &self,
+        fqdn: &DomainName,
+        zone: Option<&DomainName>,
+    ) -> Result<String, DnsError> {
+        let zone = self.removal_zone(fqdn, zone)?;
+        let update = zone.backend.describe_remove(fqdn)?;
+        Ok(format!(
+            "not removing DNS records of {} from zone {}:\n{}",
+            fqdn, &zone.name, update
+        ))
+    }
+
+    fn target_zone(&self, target: &Target) -> Result<&Zone, DnsError> {
+        target
+            .zone
+            .as_ref()
+            .and_then(|name| self.zones.iter().find(|zone| &zone.name == name))
+            .ok_or_else(|| DnsError::NoZone(target.fqdn.to_string()))
+    }
+
+    fn removal_zone(
+        &self,
+        fqdn: &DomainName,
+        zone: Option<&DomainName>,
+    ) -> Result<&Zone, DnsError> {
+        match zone {
+            Some(name) => self.zones.iter().find(|zone| &zone.name == name),
+            None => self
+                .zones
+                .iter()
+                .filter(|zone| fqdn.strip_suffix(&zone.name).is_some())
+                .max_by_key(|zone| zone.name.as_str().len()),
+        }
+        .ok_or_else(|| DnsError::NoZone(fqdn.to_string()))
+    }
+
+    fn record(zone: &Zone, target: &Target) -> Record {
+        Record {
+            fqdn: target.fqdn.clone(),
+            addresses: target.addresses.clone(),
+            ttl: zone.ttl,
+        }
+    }
+
+    pub fn new(config: &DnsConfig, global: &GlobalConfig) -> Result<Self, DnsError> {
+        let zones = config.zones(global);
+        if zones.len() > 1 && !config.zone_name.is_empty() {
+            warn!("Both dns.zone_name and dns.zones are configured. dns.zone_name is ignored");
+        }
+
+        let zones = zones
+            .iter()
+            .map(|zone| {
+                Ok(Zone {
+                    name: zone.zone_name.parse()?,
+                    ttl: zone.ttl,
+                    backend: Self::backend(zone)?,
+                })
+            })
+            .collect::<Result<Vec<_>, DnsError>>()?;
+
+        for (index, zone) in zones.iter().enumerate() {
+            if zones[..index].iter().any(|other| other.name == zone.name) {
+                return Err(DnsError::DuplicateZone(zone.name.to_string()));
+            }
+        }
+
+        Ok(Self { zones })
+    }
+
+    fn backend(zone: &ZoneConfig) -> Result<Arc<dyn DnsBackend>, DnsError> {
+        let backend: Arc<dyn DnsBackend> = match &zone.backend {
+            DnsBackendConfig::Nsupdate => Arc::new(NsupdateBackend::new(zone)?),
+            DnsBackendConfig::Hosts(hosts) => Arc::new(HostsBackend::new(hosts)),
+            DnsBackendConfig::Zonefile(zonefile) => Arc::new(ZonefileBackend::new(zonefile, zone)?),
+            DnsBackendConfig::Powerdns(powerdns) => Arc::new(PowerDnsBackend::new(powerdns, zone)?),
+        };
+
+        Ok(backend)
+    }
+}
+
+/// Run a reload command, e.g. to make a DNS server pick up changes to its files
+fn run_reload_command(command: &[String]) -> Result<(), DnsError> {
+    let (program, args) = command
+        .split_first()
+        .ok_or(DnsError::InvalidReload("reload command is empty"))?;
+
+    trace!("Running reload command {:?}", command);
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    trace!(
+        "Reload command stdout: {:?}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    trace!(
+        "Reload command stderr: {:?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if !output.status.success() {
+        return Err(DnsError::ReloadFailed(command.to_vec()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use crate::config::ZoneConfig;
+use crate::services::dns::{record_type, DnsBackend, DnsError, Record, RECORD_TYPES};
+use crate::services::naming::DomainName;
+use crate::util::find_executable;
+use log::trace;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const DNS_PORT: u16 = 53;
+/// How long to wait for the DNS server when checking whether it is reachable
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Creates records by sending a dynamic update with nsupdate
+#[derive(Debug, Clone)]
+pub struct NsupdateBackend {
+    server: String,
+    zone: DomainName,
+    key_file: Option<PathBuf>,
+}
+
+impl NsupdateBackend {
+    pub fn new(config: &ZoneConfig) -> Result<Self, DnsError> {
+        Ok(Self {
+            server: config.server.clone(),
+            zone: config.zone_name.parse()?,
+            key_file: config.key_file.clone(),
+        })
+    }
+}
+
+impl NsupdateBackend {
+    /// The nsupdate commands replacing the records of `record`
+    fn update_message(&self, record: &Record) -> Result<Vec<String>, DnsError> {
+        let name = record.fqdn.within(&self.zone)?.absolute();
+        let mut message = self.delete_message(&name, &record.record_types());
+
+        for ip in &record.addresses {
+            message.push(format!(
+                "update add {} {} {} {}",
+                name,
+                record.ttl,
+                record_type(ip),
+                ip
+            ));
+        }
+
+        message.push("send".to_string());
+        message.push("quit".to_string());
+        Ok(message)
+    }
+
+    /// The nsupdate commands removing the records of `fqdn`
+    fn remove_message(&self, fqdn: &DomainName) -> Result<Vec<String>, DnsError> {
+        let name = fqdn.within(&self.zone)?.absolute();
+        let mut message = self.delete_message(&name, &RECORD_TYPES);
+        message.push("send".to_string());
+        message.push("quit".to_string());
+        Ok(message)
+    }
+
+    /// The nsupdate commands deleting the records of `name` with one of `types`, without sending them
+    fn delete_message(&self, name: &str, types: &[&str]) -> Vec<String> {
+        let mut message = vec![
+            format!("server {}", &self.server),
+            format!("zone {}", self.zone.absolute()),
+        ];
+        message.extend(
+            types
+                .iter()
+                .map(|rtype| format!("update delete {} {}", name, rtype)),
+        );
+        message
+    }
+
+    /// Send the commands to nsupdate
+    fn send(&self, message: &[String]) -> Result<(), DnsError> {
+        let mut command = Command::new("nsupdate");
+        if let Some(key_file) = &self.key_file {
+            command.arg("-k").arg(key_file);
+        }
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.as_mut().unwrap();
+        for line in message {
+            trace!("Nsupdate: {}", line);
+            stdin.write_all(line.as_bytes())?;
+            stdin.write_all(b"\n")?;
+        }
+
+        let output = child.wait_with_output()?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        trace!("Nsupdate stdout: {:?}", stdout);
+        trace!("Nsupdate stderr: {:?}", stderr);
+
+        if !output.status.success() {
+            return Err(DnsError::NsupdateFailed);
+        }
+
+        trace!("Nsupdate completed successfully");
+        Ok(())
+    }
+}
+
+impl DnsBackend for NsupdateBackend {
+    fn add_record(&self, record: &Record) -> Result<(), DnsError> {
+        let message = self.update_message(record)?;
+        self.send(&message)
+    }
+
+    fn remove_record(&self, fqdn: &DomainName) -> Result<(), DnsError> {
+        let message = self.remove_message(fqdn)?;
+        self.send(&message)
+    }
+
+    fn describe_add(&self, record: &Record) -> Result<String, DnsError> {
+        Ok(self.update_message(record)?.join("\n"))
+    }
+
+    fn describe_remove(&self, fqdn: &DomainName) -> Result<String, DnsError> {
+        Ok(self.remove_message(fqdn)?.join("\n"))
+    }
+
+    fn check(&self) -> Result<(), DnsError> {
+        find_executable(Path::new("nsupdate")).ok_or(DnsError::NotFound("nsupdate"))?;
+        if let Some(key_file) = &self.key_file {
+            fs::File::open(key_file)?;
+        }
+
+        // The server is given as `<host> [port]`, like nsupdate's `server` command
+        let mut parts = self.server.split_whitespace();
+        let host = parts.next().unwrap_or_default();
+        let port = parts
+            .next()
+            .and_then(|x| x.parse::<u16>().ok())
+            .unwrap_or(DNS_PORT);
+
+        let unreachable = |e| DnsError::Unreachable(self.server.clone(), e);
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(unreachable)?
+            .next()
+            .ok_or_else(|| unreachable(io::ErrorKind::NotFound.into()))?;
+
+        trace!("Connecting to DNS server {}", addr);
+        TcpStream::connect_timeout(&addr, CHECK_TIMEOUT).map_err(unreachable)?;
+        Ok(())
+    }
+}
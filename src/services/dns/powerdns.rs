@@ -0,0 +1,243 @@
+use crate::config::{PowerDnsBackendConfig, ZoneConfig};
+use crate::services::dns::{record_type, DnsBackend, DnsError, Record, RECORD_TYPES};
+use crate::services::naming::DomainName;
+use log::trace;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Creates records using the PowerDNS HTTP API
+#[derive(Debug, Clone)]
+pub struct PowerDnsBackend {
+    url: String,
+    api_key: String,
+    server_id: String,
+    zone: DomainName,
+    agent: ureq::Agent,
+}
+
+#[derive(Debug, Serialize)]
+struct PatchZone<'a> {
+    rrsets: Vec<RrSet<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct RrSet<'a> {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: &'a str,
+    ttl: u64,
+    changetype: &'a str,
+    records: Vec<RrSetRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct RrSetRecord {
+    content: String,
+    disabled: bool,
+}
+
+impl PowerDnsBackend {
+    pub fn new(config: &PowerDnsBackendConfig, zone: &ZoneConfig) -> Result<Self, DnsError> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+
+        Ok(Self {
+            url: config.url.trim_end_matches('/').to_string(),
+            api_key: config.api_key.clone(),
+            server_id: config.server_id.clone(),
+            zone: zone.zone_name.parse()?,
+            agent,
+        })
+    }
+}
+
+impl DnsBackend for PowerDnsBackend {
+    fn add_record(&self, record: &Record) -> Result<(), DnsError> {
+        self.patch(self.add_patch(record)?)
+    }
+
+    fn remove_record(&self, fqdn: &DomainName) -> Result<(), DnsError> {
+        self.patch(self.remove_patch(fqdn)?)
+    }
+
+    fn describe_add(&self, record: &Record) -> Result<String, DnsError> {
+        Ok(self.describe(&self.add_patch(record)?))
+    }
+
+    fn describe_remove(&self, fqdn: &DomainName) -> Result<String, DnsError> {
+        Ok(self.describe(&self.remove_patch(fqdn)?))
+    }
+
+    fn check(&self) -> Result<(), DnsError> {
+        let url = self.zone_url();
+        trace!("PowerDNS: GET {}", url);
+
+        self.agent
+            .get(&url)
+            .set("X-API-Key", &self.api_key)
+            .call()
+            .map_err(api_error)?;
+        Ok(())
+    }
+}
+
+impl PowerDnsBackend {
+    /// The changes replacing the rrsets of `record`
+    fn add_patch(&self, record: &Record) -> Result<PatchZone<'static>, DnsError> {
+        let name = record.fqdn.within(&self.zone)?.absolute();
+        let rrsets = [
+            record.ipv4().collect::<Vec<_>>(),
+            record.ipv6().collect::<Vec<_>>(),
+        ]
+        .into_iter()
+        .filter(|addresses| !addresses.is_empty())
+        .map(|addresses| RrSet {
+            name: name.clone(),
+            rtype: record_type(addresses[0]),
+            ttl: record.ttl,
+            changetype: "REPLACE",
+            records: addresses
+                .iter()
+                .map(|ip| RrSetRecord {
+                    content: ip.to_string(),
+                    disabled: false,
+                })
+                .collect(),
+        })
+        .collect();
+
+        Ok(PatchZone { rrsets })
+    }
+
+    /// The changes deleting the rrsets of `fqdn`
+    fn remove_patch(&self, fqdn: &DomainName) -> Result<PatchZone<'static>, DnsError> {
+        let name = fqdn.within(&self.zone)?.absolute();
+        let rrsets = RECORD_TYPES
+            .iter()
+            .map(|rtype| RrSet {
+                name: name.clone(),
+                rtype,
+                ttl: 0,
+                changetype: "DELETE",
+                records: Vec::new(),
+            })
+            .collect();
+
+        Ok(PatchZone { rrsets })
+    }
+
+    /// The request `patch` would send
+    fn describe(&self, body: &PatchZone) -> String {
+        format!(
+            "PATCH {}\n{}",
+            self.zone_url(),
+            serde_json::to_string_pretty(body).unwrap_or_default()
+        )
+    }
+
+    fn patch(&self, body: PatchZone) -> Result<(), DnsError> {
+        let url = self.zone_url();
+        trace!("PowerDNS: PATCH {} {:?}", url, body);
+
+        self.agent
+            .request("PATCH", &url)
+            .set("X-API-Key", &self.api_key)
+            .send_json(&body)
+            .map_err(api_error)?;
+
+        trace!("PowerDNS update completed successfully");
+        Ok(())
+    }
+
+    fn zone_url(&self) -> String {
+        format!(
+            "{}/api/v1/servers/{}/zones/{}",
+            self.url,
+            self.server_id,
+            self.zone.absolute()
+        )
+    }
+}
+
+fn api_error(e: ureq::Error) -> DnsError {
+    match e {
+        ureq::Error::Status(code, response) => {
+            DnsError::ApiStatus(code, response.into_string().unwrap_or_default())
+        }
+        ureq::Error::Transport(t) => DnsError::ApiTransport(Box::new(t)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mock_server;
+
+    fn backend(url: &str) -> PowerDnsBackend {
+        let config = PowerDnsBackendConfig {
+            url: url.to_string(),
+            api_key: "secret".to_string(),
+            server_id: "localhost".to_string(),
+            timeout: 5,
+        };
+        let zone = ZoneConfig {
+            zone_name: "example.com".to_string(),
+            ..Default::default()
+        };
+
+        PowerDnsBackend::new(&config, &zone).unwrap()
+    }
+
+    fn record(addresses: &[&str]) -> Record {
+        Record {
+            fqdn: "foo.example.com".parse().unwrap(),
+            addresses: addresses.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn replaces_rrset() {
+        let (url, rx) = mock_server(&[204]);
+        backend(&url)
+            .add_record(&record(&["10.0.0.5", "2001:db8::5"]))
+            .unwrap();
+
+        let received = rx.recv().unwrap();
+        assert_eq!(
+            received.request_line,
+            "PATCH /api/v1/servers/localhost/zones/example.com. HTTP/1.1"
+        );
+        assert!(received
+            .headers
+            .contains(&("x-api-key".to_string(), "secret".to_string())));
+
+        let body: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "rrsets": [{
+                    "name": "foo.example.com.",
+                    "type": "A",
+                    "ttl": 300,
+                    "changetype": "REPLACE",
+                    "records": [{"content": "10.0.0.5", "disabled": false}]
+                }, {
+                    "name": "foo.example.com.",
+                    "type": "AAAA",
+                    "ttl": 300,
+                    "changetype": "REPLACE",
+                    "records": [{"content": "2001:db8::5", "disabled": false}]
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn reports_api_errors() {
+        let (url, _rx) = mock_server(&[422]);
+        let result = backend(&url).add_record(&record(&["2001:db8::5"]));
+        assert!(matches!(result, Err(DnsError::ApiStatus(422, _))));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::config::{SerialScheme, ZoneConfig, ZonefileBackendConfig};
+use crate::services::dns::{
+    diff, record_type, run_reload_command, DnsBackend, DnsError, Record, RECORD_TYPES,
+};
+use crate::services::naming::DomainName;
+use crate::util::{check_writable, file_lock, render_template, tmp_path};
+use log::{debug, trace};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Creates records by editing a BIND zone file directly.
+/// The SOA serial is incremented on every change
+#[derive(Debug)]
+pub struct ZonefileBackend {
+    path: PathBuf,
+    origin: DomainName,
+    serial: SerialScheme,
+    check_command: Option<Vec<String>>,
+    reload_command: Option<Vec<String>>,
+    /// Serializes updates to the file between concurrent jobs, also across reloads and zones sharing the file
+    lock: Arc<Mutex<()>>,
+}
+
+impl ZonefileBackend {
+    pub fn new(config: &ZonefileBackendConfig, zone: &ZoneConfig) -> Result<Self, DnsError> {
+        Ok(Self {
+            path: config.path.clone(),
+            origin: zone.zone_name.parse()?,
+            serial: config.serial,
+            check_command: config.check_command.clone(),
+            reload_command: config.reload_command.clone(),
+            lock: file_lock(&config.path),
+        })
+    }
+
+    /// Whether the zone file line is a record with the owner name of `fqdn` and one of the record `types`
+    fn is_same_record(&self, line: &str, owner: &str, fqdn: &DomainName, types: &[&str]) -> bool {
+        // Lines starting with whitespace inherit the previous owner, we never replace those
+        if line.starts_with(char::is_whitespace) {
+            return false;
+        }
+
+        let line = line.split(';').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let name = match tokens.next() {
+            Some(x) => x.to_ascii_lowercase(),
+            None => return false,
+        };
+
+        if name != owner && name != fqdn.absolute() {
+            return false;
+        }
+
+        let rtype = tokens
+            .find(|x| !x.chars().all(|c| c.is_ascii_digit()) && !x.eq_ignore_ascii_case("IN"))
+            .unwrap_or("");
+        types.iter().any(|x| rtype.eq_ignore_ascii_case(x))
+    }
+
+    /// Replace the records of `fqdn` with one of the record `types` by records for `addresses`
+    fn update_zone(
+        &self,
+        contents: &str,
+        fqdn: &DomainName,
+        types: &[&str],
+        addresses: &[IpAddr],
+        ttl: u64,
+    ) -> Result<String, DnsError> {
+        let owner = fqdn.relative_to(&self.origin);
+        let mut lines = contents
+            .lines()
+            .filter(|line| !self.is_same_record(line, &owner, fqdn, types))
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        lines.extend(
+            addresses
+                .iter()
+                .map(|ip| format!("{}\t{}\tIN\t{}\t{}", owner, ttl, record_type(ip), ip)),
+        );
+
+        let mut output = lines.join("\n");
+        output.push('\n');
+
+        let (range, serial) = find_serial(&output).ok_or(DnsError::NoSoaSerial)?;
+        let new_serial = next_serial(serial, self.serial);
+        trace!("Incrementing SOA serial from {} to {}", serial, new_serial);
+        output.replace_range(range, &new_serial.to_string());
+
+        Ok(output)
+    }
+
+    /// Update the zone file, validate it and reload
+    fn update(
+        &self,
+        fqdn: &DomainName,
+        types: &[&str],
+        addresses: &[IpAddr],
+        ttl: u64,
+    ) -> Result<(), DnsError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        trace!("Reading zone file {:?}", &self.path);
+        let contents = fs::read_to_string(&self.path)?;
+        let output = self.update_zone(&contents, fqdn, types, addresses, ttl)?;
+
+        let tmp = tmp_path(&self.path);
+        trace!("Writing zone file to {:?}", &tmp);
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(output.as_bytes())?;
+        f.sync_all()?;
+
+        if let Some(command) = &self.check_command {
+            let path = tmp.to_string_lossy();
+            let command = command
+                .iter()
+                .map(|x| render_template(x, &[("path", &path)]))
+                .collect::<Vec<_>>();
+
+            debug!("Validating zone file with {:?}", command);
+            if let Err(e) = run_reload_command(&command) {
+                fs::remove_file(&tmp)?;
+                return Err(e);
+            }
+        }
+
+        trace!("Moving {:?} to {:?}", &tmp, &self.path);
+        fs::rename(&tmp, &self.path)?;
+
+        if let Some(command) = &self.reload_command {
+            debug!("Running reload command {:?}", command);
+            run_reload_command(command)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DnsBackend for ZonefileBackend {
+    fn add_record(&self, record: &Record) -> Result<(), DnsError> {
+        self.update(
+            &record.fqdn,
+            &record.record_types(),
+            &record.addresses,
+            record.ttl,
+        )
+    }
+
+    fn remove_record(&self, fqdn: &DomainName) -> Result<(), DnsError> {
+        self.update(fqdn, &RECORD_TYPES, &[], 0)
+    }
+
+    fn describe_add(&self, record: &Record) -> Result<String, DnsError> {
+        let contents = fs::read_to_string(&self.path)?;
+        let output = self.update_zone(
+            &contents,
+            &record.fqdn,
+            &record.record_types(),
+            &record.addresses,
+            record.ttl,
+        )?;
+        Ok(diff(&contents, &output))
+    }
+
+    fn describe_remove(&self, fqdn: &DomainName) -> Result<String, DnsError> {
+        let contents = fs::read_to_string(&self.path)?;
+        let output = self.update_zone(&contents, fqdn, &RECORD_TYPES, &[], 0)?;
+        Ok(diff(&contents, &output))
+    }
+
+    fn check(&self) -> Result<(), DnsError> {
+        let contents = fs::read_to_string(&self.path)?;
+        find_serial(&contents).ok_or(DnsError::NoSoaSerial)?;
+        check_writable(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Find the serial of the SOA record.
+/// Returns the byte range of the serial in `contents`, and its value
+fn find_serial(contents: &str) -> Option<(std::ops::Range<usize>, u32)> {
+    let tokens = tokenize(contents);
+    let soa = tokens
+        .iter()
+        .position(|(_, token)| token.eq_ignore_ascii_case("SOA"))?;
+
+    // SOA <mname> <rname> ( <serial> ...
+    let (start, serial) = tokens
+        .iter()
+        .skip(soa + 3)
+        .find(|(_, token)| *token != "(")?;
+    let value = serial.parse::<u32>().ok()?;
+    Some((*start..*start + serial.len(), value))
+}
+
+/// Split a zone file into tokens with their byte offsets, skipping comments.
+/// Parentheses are separate tokens
+fn tokenize(contents: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut in_quotes = false;
+    let mut in_comment = false;
+
+    let mut chars = contents.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        if in_comment {
+            in_comment = c != '\n';
+            continue;
+        }
+
+        if in_quotes {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        if c.is_whitespace() || matches!(c, ';' | '(' | ')') {
+            if let Some(s) = start.take() {
+                tokens.push((s, &contents[s..idx]));
+            }
+
+            match c {
+                ';' => in_comment = true,
+                '(' | ')' => tokens.push((idx, &contents[idx..idx + 1])),
+                _ => {}
+            }
+        } else {
+            in_quotes = c == '"';
+            start.get_or_insert(idx);
+        }
+    }
+
+    if let Some(s) = start {
+        tokens.push((s, &contents[s..]));
+    }
+
+    tokens
+}
+
+fn next_serial(current: u32, scheme: SerialScheme) -> u32 {
+    match scheme {
+        SerialScheme::Integer => current.wrapping_add(1),
+        SerialScheme::Date => {
+            let today = time::OffsetDateTime::now_utc().date();
+            let base = today.year() as u32 * 1_000_000
+                + u8::from(today.month()) as u32 * 10_000
+                + today.day() as u32 * 100;
+            base.max(current.wrapping_add(1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "$ORIGIN example.com.
+@\t3600\tIN\tSOA\tns1.example.com. hostmaster.example.com. (
+\t\t2020010101 ; serial
+\t\t3600 900 604800 300 )
+\tIN\tNS\tns1.example.com.
+foo\t300\tIN\tA\t10.0.0.1
+FOO.example.com.\tIN\tAAAA\t2001:db8::1
+foo\t300\tIN\tTXT\t\"v=spf1 ; not a comment\"
+foobar\t300\tIN\tA\t10.0.0.2
+";
+
+    fn backend(serial: SerialScheme) -> ZonefileBackend {
+        ZonefileBackend {
+            path: PathBuf::from("db.example.com"),
+            origin: "example.com".parse().unwrap(),
+            serial,
+            check_command: None,
+            reload_command: None,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    #[test]
+    fn tokenizes_comments_parentheses_and_quotes() {
+        let tokens = tokenize("a (b;c d\n\"e ; f\" )g")
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["a", "(", "b", "\"e ; f\"", ")", "g"]);
+    }
+
+    #[test]
+    fn finds_serial_in_parentheses() {
+        let (range, serial) = find_serial(ZONE).unwrap();
+        assert_eq!(serial, 2020010101);
+        assert_eq!(&ZONE[range], "2020010101");
+    }
+
+    #[test]
+    fn finds_serial_on_one_line() {
+        let zone = "@ IN SOA ns1 hostmaster 5 3600 900 604800 300\n";
+        assert_eq!(find_serial(zone).map(|(_, serial)| serial), Some(5));
+        assert_eq!(find_serial("foo IN A 10.0.0.1\n"), None);
+    }
+
+    #[test]
+    fn increments_serial() {
+        assert_eq!(next_serial(5, SerialScheme::Integer), 6);
+        assert_eq!(next_serial(u32::MAX, SerialScheme::Integer), 0);
+
+        let serial = next_serial(2020010101, SerialScheme::Date);
+        assert!(serial > 2020010101);
+        assert_eq!(serial % 100, 0);
+        assert_eq!(next_serial(4000010100, SerialScheme::Date), 4000010101);
+    }
+
+    #[test]
+    fn replaces_records_of_the_same_owner_and_type() {
+        let fqdn = "foo.example.com".parse().unwrap();
+        let output = backend(SerialScheme::Integer)
+            .update_zone(ZONE, &fqdn, &["A"], &["10.0.0.5".parse().unwrap()], 300)
+            .unwrap();
+
+        assert!(!output.contains("10.0.0.1"));
+        assert!(output.contains("FOO.example.com.\tIN\tAAAA\t2001:db8::1"));
+        assert!(output.contains("\"v=spf1 ; not a comment\""));
+        assert!(output.contains("foobar\t300\tIN\tA\t10.0.0.2"));
+        assert!(output.contains("\tIN\tNS\tns1.example.com."));
+        assert!(output.ends_with("foo\t300\tIN\tA\t10.0.0.5\n"));
+        assert!(output.contains("2020010102 ; serial"));
+    }
+
+    #[test]
+    fn removes_relative_and_absolute_owners() {
+        let fqdn = "foo.example.com".parse().unwrap();
+        let output = backend(SerialScheme::Integer)
+            .update_zone(ZONE, &fqdn, &RECORD_TYPES, &[], 0)
+            .unwrap();
+
+        assert!(!output.contains("10.0.0.1"));
+        assert!(!output.contains("2001:db8::1"));
+        assert!(output.contains("v=spf1"));
+        assert!(output.contains("foobar"));
+        assert!(output.contains("2020010102 ; serial"));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::config::ApprovalConfig;
+use crate::logging::JobContext;
+use crate::services::naming::{DomainName, NameError};
+use crate::services::registry::{Registration, TakeOver};
+use crate::services::Target;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ApprovalError {
+    #[error("Approval is not configured")]
+    Disabled,
+    #[error("Invalid admin token")]
+    Unauthorized,
+    #[error("No pending job with ID {0}")]
+    UnknownJob(u64),
+}
+
+/// A phone-home waiting for approval by an operator
+#[derive(Debug)]
+pub struct PendingJob {
+    pub context: JobContext,
+    pub target: Target,
+    /// The registration to store when the job is approved. `None` if there is no registry, or for dry runs
+    pub registration: Option<Registration>,
+    /// How the job may take its name over from another machine once it is approved
+    pub take_over: Option<TakeOver>,
+    reason: String,
+    created: i64,
+}
+
+/// A description of a pending job, as shown to operators
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingJobInfo {
+    pub id: u64,
+    pub hostname: String,
+    pub fqdn: String,
+    pub ip: String,
+    pub addresses: Vec<IpAddr>,
+    pub reason: String,
+    pub created: i64,
+    pub expires: i64,
+}
+
+/// Holds jobs until they are approved, rejected, or expire.
+/// Pending jobs are kept in memory and are lost when Ordin restarts
+#[derive(Debug)]
+pub struct PendingJobs {
+    admin_token: String,
+    require: bool,
+    zones: Vec<DomainName>,
+    expire_after: i64,
+    /// Shared with the pending jobs of reloaded configurations
+    jobs: Arc<Mutex<BTreeMap<u64, PendingJob>>>,
+}
+
+impl PendingJobs {
+    pub fn new(config: &ApprovalConfig) -> Result<Self, NameError> {
+        Ok(Self {
+            admin_token: config.admin_token.clone(),
+            require: config.require,
+            zones: config
+                .zones
+                .iter()
+                .map(|x| x.parse())
+                .collect::<Result<_, _>>()?,
+            expire_after: config.expire_after as i64,
+            jobs: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    /// Pending jobs with a new configuration, sharing the jobs of these
+    pub fn reconfigure(&self, config: &ApprovalConfig) -> Result<Self, NameError> {
+        Ok(Self {
+            jobs: self.jobs.clone(),
+            ..Self::new(config)?
+        })
+    }
+
+    pub fn admin_token(&self) -> &str {
+        &self.admin_token
+    }
+
+    /// Whether jobs for `target` require approval, regardless of conflicts in the registry
+    pub fn requires_approval(&self, target: &Target) -> bool {
+        if !self.require {
+            return false;
+        }
+
+        self.zones.is_empty()
+            || target
+                .zone
+                .as_ref()
+                .map(|zone| self.zones.contains(zone))
+                .unwrap_or(false)
+    }
+
+    /// Hold a job for approval. Returns the ID of the job.
+    /// If a job for the same machine is already pending, that job's ID is returned instead
+    pub fn add(
+        &self,
+        context: JobContext,
+        target: Target,
+        registration: Option<Registration>,
+        take_over: Option<TakeOver>,
+        reason: String,
+    ) -> u64 {
+        let mut jobs = self.lock();
+        let existing = jobs
+            .iter()
+            .find(|(_, job)| job.target.fqdn == target.fqdn && job.target.ip == target.ip);
+        if let Some((id, _)) = existing {
+            debug!(
+                "A job for {} ({}) is already pending",
+                target.fqdn, target.ip
+            );
+            return *id;
+        }
+
+        let id = context.id;
+        info!(
+            "Job {} for {} ({}) awaits approval: {}",
+            id, target.fqdn, target.ip, reason
+        );
+
+        jobs.insert(
+            id,
+            PendingJob {
+                context,
+                target,
+                registration,
+                take_over,
+                reason,
+                created: now(),
+            },
+        );
+        id
+    }
+
+    /// All jobs that have not yet expired
+    pub fn list(&self) -> Vec<PendingJobInfo> {
+        self.lock()
+            .iter()
+            .map(|(id, job)| PendingJobInfo {
+                id: *id,
+                hostname: job.target.hostname.clone(),
+                fqdn: job.target.fqdn.to_string(),
+                ip: job.target.ip.clone(),
+                addresses: job.target.addresses.clone(),
+                reason: job.reason.clone(),
+                created: job.created,
+                expires: job.created + self.expire_after,
+            })
+            .collect()
+    }
+
+    /// The number of jobs that have not yet expired
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Remove a job from the pending jobs, to approve or reject it
+    pub fn take(&self, id: u64) -> Result<PendingJob, ApprovalError> {
+        self.lock().remove(&id).ok_or(ApprovalError::UnknownJob(id))
+    }
+
+    /// Lock the jobs, removing expired jobs
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, PendingJob>> {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let now = now();
+        jobs.retain(|id, job| {
+            let expired = job.created + self.expire_after < now;
+            if expired {
+                info!(
+                    "Job {} for {} expired without being approved",
+                    id, job.target.fqdn
+                );
+            }
+            !expired
+        });
+        jobs
+    }
+}
+
+fn now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
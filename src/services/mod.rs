@@ -1,26 +1,121 @@
+use crate::services::naming::DomainName;
+use log::trace;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub mod access;
+pub mod addresses;
 pub mod ansible;
+pub mod auth;
+pub mod command;
 pub mod dns;
+pub mod jobs;
+pub mod naming;
+pub mod pipeline;
+pub mod proxies;
+pub mod registry;
+pub mod throttle;
+pub mod webhook;
 
 #[derive(Debug)]
 pub struct Target {
+    /// The address the machine phoned home from
     pub ip: String,
+    /// All addresses of the machine, including `ip`
+    pub addresses: Vec<IpAddr>,
     pub hostname: String,
+    /// The fully qualified domain name of the machine
+    pub fqdn: DomainName,
+    /// The DNS zone the machine belongs to, if any
+    pub zone: Option<DomainName>,
+    /// Whether the services only report the changes they would make, instead of making them
+    pub dry_run: bool,
 }
 
 impl Target {
-    pub fn new<S, S1>(ip: S, hostname: S1) -> Self
+    pub fn new<S, S1>(
+        ip: S,
+        addresses: Vec<IpAddr>,
+        hostname: S1,
+        fqdn: DomainName,
+        zone: Option<DomainName>,
+    ) -> Self
     where
         S: AsRef<str>,
         S1: AsRef<str>,
     {
         Self {
             ip: ip.as_ref().to_string(),
+            addresses,
             hostname: hostname.as_ref().to_string(),
+            fqdn,
+            zone,
+            dry_run: false,
         }
     }
 }
 
+/// Writes the output of child processes to the play-log directory
+#[derive(Debug, Clone)]
+pub struct PlayLog {
+    dir: PathBuf,
+    enabled: bool,
+}
+
+impl PlayLog {
+    pub fn new(dir: &Path, enabled: bool) -> io::Result<Self> {
+        if enabled && !dir.exists() {
+            trace!("Play logging directory {:?} does not exist, creating", dir);
+            fs::create_dir_all(dir)?;
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            enabled,
+        })
+    }
+
+    /// Write the output of a child process to the log directory, if logging is enabled.
+    /// `kind` and `name` describe what was run, e.g. `ansible_playbook` and the playbook's filename
+    pub fn write(
+        &self,
+        kind: &str,
+        name: &str,
+        target: &Target,
+        stdout: &str,
+        stderr: &str,
+    ) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let path = self.dir.join(format!(
+            "{}-{}_{}_{}-{}.log",
+            time::OffsetDateTime::now_utc().unix_timestamp(),
+            kind,
+            name,
+            target.ip,
+            target.hostname
+        ));
+        trace!("Play logging is enabled. Logging to {:?}", &path);
+        let mut f = fs::File::create(path)?;
+
+        f.write_all(b"STDOUT:\n")?;
+        f.write_all(stdout.as_bytes())?;
+        f.write_all(b"\nSTDERR:\n")?;
+        f.write_all(stderr.as_bytes())?;
+        Ok(())
+    }
+}
+
 pub trait Service {
     type Err;
     fn run(&self, target: &Target) -> Result<(), Self::Err>;
+
+    /// Verify that the service is able to run, e.g. that the files and programs it uses are available
+    fn check(&self) -> Result<(), Self::Err> {
+        Ok(())
+    }
 }
@@ -0,0 +1,421 @@
+use crate::config::Config;
+use crate::services::Target;
+use ipnet::IpNet;
+use log::trace;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The maximum length of a domain name, excluding the trailing dot
+const MAX_NAME_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Debug, Error)]
+pub enum NameError {
+    #[error("Domain name is empty")]
+    Empty,
+    #[error("Domain name {0:?} is longer than 253 characters")]
+    TooLong(String),
+    #[error("Label {0:?} is empty or longer than 63 characters")]
+    LabelLength(String),
+    #[error("Label {0:?} may only contain letters, digits and hyphens")]
+    InvalidCharacter(String),
+    #[error("Label {0:?} may not start or end with a hyphen")]
+    Hyphen(String),
+    #[error("Hostname {0:?} must be a single label")]
+    NotALabel(String),
+    #[error("{0:?} is the domain of a zone, not the name of a machine")]
+    ZoneDomain(String),
+}
+
+/// A validated domain name following the RFC 1123 hostname rules.
+/// Names are normalized to lowercase and stored without a trailing dot
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DomainName(String);
+
+impl FromStr for DomainName {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.strip_suffix('.').unwrap_or(s).to_ascii_lowercase();
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(NameError::TooLong(name));
+        }
+
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+                return Err(NameError::LabelLength(label.to_string()));
+            }
+
+            if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(NameError::InvalidCharacter(label.to_string()));
+            }
+
+            if label.starts_with('-') || label.ends_with('-') {
+                return Err(NameError::Hyphen(label.to_string()));
+            }
+        }
+
+        Ok(Self(name))
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl DomainName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The leftmost label, e.g. `foo` for `foo.example.com`
+    pub fn first_label(&self) -> &str {
+        self.0.split('.').next().unwrap_or(&self.0)
+    }
+
+    /// The name with a trailing dot
+    pub fn absolute(&self) -> String {
+        format!("{}.", self.0)
+    }
+
+    /// Whether this name is equal to, or a subdomain of, `other`
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.0 == other.0 || self.0.ends_with(&format!(".{}", other.0))
+    }
+
+    /// Append `parent` to this name, e.g. `foo` and `example.com` become `foo.example.com`
+    pub fn join(&self, parent: &DomainName) -> Result<DomainName, NameError> {
+        format!("{}.{}", self.0, parent.0).parse()
+    }
+
+    /// This name if it is a proper subdomain of `zone`, otherwise this name with `zone` appended.
+    /// The zone itself is never returned, so its apex records can't be replaced
+    pub fn within(&self, zone: &DomainName) -> Result<DomainName, NameError> {
+        if self.strip_suffix(zone).is_some() {
+            Ok(self.clone())
+        } else {
+            self.join(zone)
+        }
+    }
+
+    /// The labels of this name left of `suffix`, if this name is a proper subdomain of `suffix`
+    pub fn strip_suffix(&self, suffix: &DomainName) -> Option<DomainName> {
+        self.0
+            .strip_suffix(&format!(".{}", suffix.0))
+            .map(|x| Self(x.to_string()))
+    }
+
+    /// The name relative to `origin` as used in zone files:
+    /// `@` for the origin itself, the relative labels for names within the origin,
+    /// and the absolute name otherwise
+    pub fn relative_to(&self, origin: &DomainName) -> String {
+        if self == origin {
+            return "@".to_string();
+        }
+
+        match self.strip_suffix(origin) {
+            Some(relative) => relative.0,
+            None => self.absolute(),
+        }
+    }
+}
+
+/// Determines the fully qualified domain name and DNS zone of machines
+#[derive(Debug, Clone)]
+pub struct Naming {
+    zones: Vec<ZoneRule>,
+    domain: Option<DomainName>,
+}
+
+#[derive(Debug, Clone)]
+struct ZoneRule {
+    zone_name: DomainName,
+    domain: DomainName,
+    suffixes: Vec<DomainName>,
+    subnets: Vec<IpNet>,
+}
+
+impl ZoneRule {
+    /// Whether the zone has no matching rules of its own, making it a fallback
+    fn is_fallback(&self) -> bool {
+        self.suffixes.is_empty() && self.subnets.is_empty()
+    }
+
+    /// The longest suffix of `name` matching this zone, if any
+    fn matching_suffix(&self, name: &DomainName) -> Option<&DomainName> {
+        std::iter::once(&self.domain)
+            .chain(self.suffixes.iter())
+            .filter(|suffix| name.is_subdomain_of(suffix))
+            .max_by_key(|suffix| suffix.as_str().len())
+    }
+
+    /// The FQDN of `name` in this zone. `suffix` is the matched suffix of the name, if any.
+    /// The zone's domain and suffixes themselves are rejected, they are not names of machines
+    fn fqdn(
+        &self,
+        name: &DomainName,
+        suffix: Option<&DomainName>,
+    ) -> Result<DomainName, NameError> {
+        if name == &self.domain || suffix == Some(name) {
+            return Err(NameError::ZoneDomain(name.to_string()));
+        }
+
+        if name.strip_suffix(&self.domain).is_some() {
+            return Ok(name.clone());
+        }
+
+        suffix
+            .and_then(|suffix| name.strip_suffix(suffix))
+            .unwrap_or_else(|| name.clone())
+            .join(&self.domain)
+    }
+}
+
+impl Naming {
+    pub fn new(config: &Config) -> Result<Self, NameError> {
+        let zones = config
+            .dns
+            .as_ref()
+            .map(|dns| dns.zones(&config.global))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|zone| {
+                Ok(ZoneRule {
+                    domain: zone.domain().parse()?,
+                    zone_name: zone.zone_name.parse()?,
+                    suffixes: zone
+                        .suffixes
+                        .iter()
+                        .map(|x| x.parse())
+                        .collect::<Result<_, _>>()?,
+                    subnets: zone.subnets,
+                })
+            })
+            .collect::<Result<Vec<_>, NameError>>()?;
+
+        let domain = match config.global.domain.as_str() {
+            "" => None,
+            domain => Some(domain.parse()?),
+        };
+
+        Ok(Self { zones, domain })
+    }
+
+    /// Create the target for a machine.
+    /// `addresses` are all addresses of the machine, `fqdn` is the fully qualified name posted by the machine, if any.
+    ///
+    /// The zone is chosen by, in order:
+    /// 1. The longest zone domain or suffix matching the posted FQDN or hostname
+    /// 2. The first zone with a subnet containing the machine's IP address
+    /// 3. The first zone without any matching rules
+    pub fn target(
+        &self,
+        ip: &str,
+        addresses: Vec<IpAddr>,
+        hostname: &str,
+        fqdn: Option<&str>,
+    ) -> Result<Target, NameError> {
+        // The hostname ends up in file names, templates and command arguments, so it is validated
+        // even if the machine is named after its FQDN
+        let name: DomainName = match fqdn {
+            Some(fqdn) => {
+                let label = hostname.parse::<DomainName>()?;
+                if label.as_str().contains('.') {
+                    return Err(NameError::NotALabel(hostname.to_string()));
+                }
+                fqdn.parse()?
+            }
+            None => hostname.parse()?,
+        };
+
+        let by_suffix = self
+            .zones
+            .iter()
+            .filter_map(|zone| zone.matching_suffix(&name).map(|suffix| (zone, suffix)))
+            .max_by_key(|(_, suffix)| suffix.as_str().len());
+        if let Some((zone, suffix)) = by_suffix {
+            trace!("{} matches zone {} by suffix", name, &zone.zone_name);
+            return Ok(Target::new(
+                ip,
+                addresses,
+                hostname,
+                zone.fqdn(&name, Some(suffix))?,
+                Some(zone.zone_name.clone()),
+            ));
+        }
+
+        let addr = ip.parse::<IpAddr>().ok();
+        let by_subnet = self.zones.iter().find(|zone| {
+            addr.map(|addr| zone.subnets.iter().any(|net| net.contains(&addr)))
+                .unwrap_or(false)
+        });
+        if let Some(zone) = by_subnet {
+            trace!("{} matches zone {} by subnet", ip, &zone.zone_name);
+            return Ok(Target::new(
+                ip,
+                addresses,
+                hostname,
+                zone.fqdn(&name, None)?,
+                Some(zone.zone_name.clone()),
+            ));
+        }
+
+        if let Some(zone) = self.zones.iter().find(|zone| zone.is_fallback()) {
+            trace!("Using fallback zone {} for {}", &zone.zone_name, name);
+            return Ok(Target::new(
+                ip,
+                addresses,
+                hostname,
+                zone.fqdn(&name, None)?,
+                Some(zone.zone_name.clone()),
+            ));
+        }
+
+        trace!("No zone matches {}", name);
+        let fqdn = match &self.domain {
+            Some(domain) if &name == domain => return Err(NameError::ZoneDomain(name.to_string())),
+            Some(domain) if fqdn.is_none() => name.within(domain)?,
+            _ => name,
+        };
+        Ok(Target::new(ip, addresses, hostname, fqdn, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DnsConfig, ZoneConfig};
+
+    fn name(name: &str) -> DomainName {
+        name.parse().unwrap()
+    }
+
+    fn naming() -> Naming {
+        let mut config = Config::default();
+        config.global.domain = "example.com".to_string();
+        config.dns = Some(DnsConfig {
+            zones: vec![ZoneConfig {
+                zone_name: "example.com".to_string(),
+                suffixes: vec!["lab.example.net".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        Naming::new(&config).unwrap()
+    }
+
+    fn fqdn(hostname: &str, fqdn: Option<&str>) -> Result<String, NameError> {
+        naming()
+            .target("10.0.0.5", Vec::new(), hostname, fqdn)
+            .map(|target| target.fqdn.to_string())
+    }
+
+    #[test]
+    fn normalizes_case_and_trailing_dot() {
+        assert_eq!(name("Foo.Example.COM.").as_str(), "foo.example.com");
+        assert_eq!(name("foo.example.com."), name("foo.example.com"));
+        assert!(matches!("".parse::<DomainName>(), Err(NameError::Empty)));
+        assert!(matches!(".".parse::<DomainName>(), Err(NameError::Empty)));
+    }
+
+    #[test]
+    fn enforces_label_rules() {
+        assert!(format!("{}.com", "a".repeat(63))
+            .parse::<DomainName>()
+            .is_ok());
+        assert!(matches!(
+            format!("{}.com", "a".repeat(64)).parse::<DomainName>(),
+            Err(NameError::LabelLength(_))
+        ));
+        assert!(matches!(
+            "foo..com".parse::<DomainName>(),
+            Err(NameError::LabelLength(_))
+        ));
+        assert!(matches!(
+            "foo_bar.com".parse::<DomainName>(),
+            Err(NameError::InvalidCharacter(_))
+        ));
+        assert!(matches!(
+            "../etc".parse::<DomainName>(),
+            Err(NameError::LabelLength(_))
+        ));
+        assert!(matches!(
+            "-foo.com".parse::<DomainName>(),
+            Err(NameError::Hyphen(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_name_length() {
+        let label = "a".repeat(63);
+        let longest = format!("{0}.{0}.{0}.{1}", label, "a".repeat(61));
+        assert_eq!(longest.len(), 253);
+        assert!(longest.parse::<DomainName>().is_ok());
+        assert!(matches!(
+            format!("a{}", longest).parse::<DomainName>(),
+            Err(NameError::TooLong(_))
+        ));
+    }
+
+    #[test]
+    fn matches_whole_labels() {
+        assert!(name("foo.rpz").is_subdomain_of(&name("rpz")));
+        assert!(!name("foorpz").is_subdomain_of(&name("rpz")));
+        assert_eq!(
+            name("foo.rpz").strip_suffix(&name("rpz")),
+            Some(name("foo"))
+        );
+        assert_eq!(name("foorpz").strip_suffix(&name("rpz")), None);
+        assert_eq!(name("rpz").strip_suffix(&name("rpz")), None);
+    }
+
+    #[test]
+    fn names_machines_in_zone() {
+        assert_eq!(fqdn("foo", None).unwrap(), "foo.example.com");
+        assert_eq!(fqdn("Foo.Example.com.", None).unwrap(), "foo.example.com");
+        assert_eq!(
+            fqdn("foo.lab.example.net", None).unwrap(),
+            "foo.example.com"
+        );
+        assert_eq!(
+            fqdn("foo", Some("foo.example.com")).unwrap(),
+            "foo.example.com"
+        );
+    }
+
+    #[test]
+    fn rejects_zone_domains() {
+        assert!(matches!(
+            fqdn("example.com", None),
+            Err(NameError::ZoneDomain(_))
+        ));
+        assert!(matches!(
+            fqdn("foo", Some("Example.com.")),
+            Err(NameError::ZoneDomain(_))
+        ));
+        assert!(matches!(
+            fqdn("lab.example.net", None),
+            Err(NameError::ZoneDomain(_))
+        ));
+        assert_eq!(
+            name("example.com").within(&name("example.com")).unwrap(),
+            name("example.com.example.com")
+        );
+    }
+
+    #[test]
+    fn rejects_dotted_hostname_with_fqdn() {
+        assert!(matches!(
+            fqdn("a.b", Some("foo.example.com")),
+            Err(NameError::NotALabel(_))
+        ));
+    }
+}
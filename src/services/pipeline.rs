@@ -0,0 +1,194 @@
+use crate::config::{Config, PipelineEntry};
+use crate::error::ServiceError;
+use crate::services::ansible::{AnsibleError, AnsibleService};
+use crate::services::command::{CommandError, CommandService};
+use crate::services::dns::{DnsError, DnsService};
+use crate::services::webhook::{Outcome, WebhookService};
+use crate::services::{Service, Target};
+use log::{debug, error, info, trace};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error("Service '{0}' is part of the pipeline, but it has not been configured")]
+    NotConfigured(&'static str),
+    #[error("Failed to create DNS service {0:?}")]
+    Dns(#[from] DnsError),
+    #[error("Failed to create Ansible service {0:?}")]
+    Ansible(#[from] AnsibleError),
+    #[error("Failed to create command service {0:?}")]
+    Command(#[from] CommandError),
+}
+
+/// A single step in the pipeline
+#[derive(Debug, Clone)]
+pub enum Step {
+    Dns(DnsService),
+    Ansible(AnsibleService),
+    Webhook(WebhookService),
+    Command(CommandService),
+}
+
+impl Step {
+    fn new(entry: &PipelineEntry, config: &Config) -> Result<Self, PipelineError> {
+        let step = match entry {
+            PipelineEntry::Dns => {
+                let dns = config
+                    .dns
+                    .as_ref()
+                    .ok_or(PipelineError::NotConfigured("dns"))?;
+                Self::Dns(DnsService::new(dns, &config.global)?)
+            }
+            PipelineEntry::Ansible => {
+                let ansible = config
+                    .ansible
+                    .as_ref()
+                    .ok_or(PipelineError::NotConfigured("ansible"))?;
+                Self::Ansible(AnsibleService::new(ansible)?)
+            }
+            PipelineEntry::Webhook(webhook) => Self::Webhook(WebhookService::new(webhook)),
+            PipelineEntry::Command(command) => {
+                Self::Command(CommandService::new(command, config.ansible.as_ref())?)
+            }
+        };
+
+        Ok(step)
+    }
+
+    /// The name of the service, as used in the configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dns(_) => "dns",
+            Self::Ansible(_) => "ansible",
+            Self::Webhook(_) => "webhook",
+            Self::Command(_) => "command",
+        }
+    }
+
+    /// Describe the changes the step would make for `target`, without making them
+    pub fn dry_run(&self, target: &Target) -> Result<String, ServiceError> {
+        let report = match self {
+            Self::Dns(dns) => dns.dry_run(target)?,
+            Self::Ansible(ansible) => ansible.dry_run(target)?,
+            // Webhooks and commands can't report what they would do, so they are skipped in dry runs
+            Self::Webhook(_) | Self::Command(_) => {
+                format!("skipping {} step for {}", self.name(), target.fqdn)
+            }
+        };
+
+        Ok(report)
+    }
+}
+
+impl Service for Step {
+    type Err = ServiceError;
+
+    fn run(&self, target: &Target) -> Result<(), Self::Err> {
+        match self {
+            Self::Webhook(_) | Self::Command(_) if target.dry_run => {
+                info!("Dry run: {}", self.dry_run(target)?)
+            }
+            Self::Dns(dns) => dns.run(target)?,
+            Self::Ansible(ansible) => ansible.run(target)?,
+            Self::Webhook(webhook) => webhook.run(target)?,
+            Self::Command(command) => command.run(target)?,
+        }
+
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), Self::Err> {
+        match self {
+            Self::Dns(dns) => dns.check()?,
+            Self::Ansible(ansible) => ansible.check()?,
+            Self::Webhook(webhook) => webhook.check()?,
+            Self::Command(command) => command.check()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// The ordered list of services run for every phone-home
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Pipeline {
+    pub fn new(config: &Config) -> Result<Self, PipelineError> {
+        let steps = config
+            .pipeline
+            .iter()
+            .map(|entry| Step::new(entry, config))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { steps })
+    }
+}
+
+impl Service for Pipeline {
+    type Err = ServiceError;
+
+    fn run(&self, target: &Target) -> Result<(), Self::Err> {
+        debug!("Running pipeline for {:?}", target);
+        for (index, step) in self.steps.iter().enumerate() {
+            trace!(
+                "Running pipeline step {}/{} ({}) for {:?}",
+                index + 1,
+                self.steps.len(),
+                step.name(),
+                target
+            );
+            if let Err(e) = step.run(target) {
+                self.notify_failure(target, index + 1, &e);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Pipeline {
+    /// Describe the changes every step would make for `target`, without making them.
+    /// Returns the name and report of each step
+    pub fn dry_run(&self, target: &Target) -> Result<Vec<(&'static str, String)>, ServiceError> {
+        self.steps
+            .iter()
+            .map(|step| Ok((step.name(), step.dry_run(target)?)))
+            .collect()
+    }
+
+    /// Check every step of the pipeline. Returns the name and check result of each step
+    pub fn check_steps(&self) -> Vec<(&'static str, Result<(), ServiceError>)> {
+        self.steps
+            .iter()
+            .map(|step| {
+                trace!("Checking pipeline step {}", step.name());
+                (step.name(), step.check())
+            })
+            .collect()
+    }
+
+    /// Call the webhooks after `from` which should be notified of a failed pipeline, unless it was a dry run
+    fn notify_failure(&self, target: &Target, from: usize, err: &ServiceError) {
+        if target.dry_run {
+            return;
+        }
+
+        let outcome = Outcome::Failure(err.to_string());
+        self.steps
+            .iter()
+            .skip(from)
+            .filter_map(|step| match step {
+                Step::Webhook(webhook) if webhook.triggers_on(&outcome) => Some(webhook),
+                _ => None,
+            })
+            .for_each(|webhook| {
+                if let Err(e) = webhook.notify(target, &outcome) {
+                    error!("Failed to notify webhook of failure: {:?}", e);
+                }
+            });
+    }
+}
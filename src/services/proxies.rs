@@ -0,0 +1,113 @@
+use crate::config::{ForwardedHeader, GlobalConfig};
+use ipnet::IpNet;
+use log::trace;
+use std::net::IpAddr;
+
+/// Determines the address of a client connecting through trusted reverse proxies
+#[derive(Debug, Clone)]
+pub struct TrustedProxies {
+    proxies: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &GlobalConfig) -> Self {
+        Self {
+            proxies: config.trusted_proxies.clone(),
+            header: config.forwarded_header,
+        }
+    }
+
+    /// The header the proxies forward the client address in. Other forwarding headers are ignored,
+    /// as proxies pass them on from the client unchanged
+    pub fn header(&self) -> ForwardedHeader {
+        self.header
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client, given the address of the peer and the addresses from the forwarding headers,
+    /// in the order in which they were added by the proxies. `None` entries are addresses that could not be parsed.
+    ///
+    /// Starting at the peer, hops are followed towards the client for as long as the hop is a trusted proxy.
+    /// Returns `None` if a trusted proxy forwarded an address that could not be parsed
+    pub fn client_ip(&self, peer: IpAddr, forwarded: &[Option<IpAddr>]) -> Option<IpAddr> {
+        let mut client = peer;
+        for hop in forwarded.iter().rev() {
+            if !self.is_trusted(&client) {
+                break;
+            }
+
+            trace!("{} is a trusted proxy, forwarded for {:?}", client, hop);
+            client = (*hop)?;
+        }
+
+        Some(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(nets: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(&GlobalConfig {
+            trusted_proxies: nets.iter().map(|net| net.parse().unwrap()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_addresses_prepended_by_the_client() {
+        // The client sent `X-Forwarded-For: 192.0.2.66`, the proxy appended the client's real address
+        let proxies = proxies(&["10.0.0.1/32"]);
+        let forwarded = [Some(ip("192.0.2.66")), Some(ip("198.51.100.7"))];
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &forwarded),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[test]
+    fn follows_chain_of_trusted_proxies() {
+        let proxies = proxies(&["10.0.0.0/24"]);
+        let forwarded = [
+            Some(ip("192.0.2.66")),
+            Some(ip("198.51.100.7")),
+            Some(ip("10.0.0.2")),
+            Some(ip("10.0.0.3")),
+        ];
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &forwarded),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_address_from_trusted_proxy() {
+        let proxies = proxies(&["10.0.0.1/32"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &[None]), None);
+    }
+
+    #[test]
+    fn ignores_unknown_address_beyond_untrusted_hop() {
+        let proxies = proxies(&["10.0.0.1/32"]);
+        let forwarded = [None, Some(ip("198.51.100.7"))];
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &forwarded),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[test]
+    fn uses_peer_without_forwarded_addresses() {
+        let proxies = proxies(&["10.0.0.1/32"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &[]), Some(ip("10.0.0.1")));
+    }
+}
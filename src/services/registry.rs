@@ -0,0 +1,198 @@
+use crate::config::{ConflictPolicy, RegistryConfig};
+use crate::services::naming::DomainName;
+use crate::util::{file_lock, tmp_path};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid registry file: {0}")]
+    Json(#[from] serde_json::Error),
+    /// The owner of the name is only logged, it is not revealed to the client
+    #[error("{0} is already registered")]
+    Conflict(DomainName),
+}
+
+/// The machine registered to a name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    /// The cloud-init instance ID of the machine, if it posted one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// The address the machine phoned home from
+    pub ip: IpAddr,
+    /// UNIX timestamp of the registration
+    pub registered: i64,
+}
+
+impl Registration {
+    pub fn new(instance_id: Option<&str>, ip: IpAddr) -> Self {
+        Self {
+            instance_id: instance_id.map(str::to_string),
+            ip,
+            registered: time::OffsetDateTime::now_utc().unix_timestamp(),
+        }
+    }
+
+    /// Whether both registrations belong to the same machine.
+    /// Machines are identified by their instance ID if both have one, and by their address otherwise
+    fn is_same_machine(&self, other: &Registration) -> bool {
+        match (&self.instance_id, &other.instance_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.ip == other.ip,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.instance_id {
+            Some(id) => format!("instance {} ({})", id, self.ip),
+            None => self.ip.to_string(),
+        }
+    }
+}
+
+/// The result of claiming a name
+#[derive(Debug)]
+pub enum Claim {
+    /// The name is registered to the machine
+    Registered,
+    /// The name is registered to another machine, and the claim must be approved by an operator.
+    /// Contains a description of the conflict
+    NeedsApproval(String),
+}
+
+/// An explicit request to take a name over from the machine it is registered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeOver {
+    /// An authenticated phone-home asked for it with `override=true`, honored with the `override` policy
+    Requested,
+    /// An operator approved the job claiming the name, honored with the `approve` policy
+    Approved,
+}
+
+/// Keeps track of which machine owns which name, stored as a JSON file.
+/// The file is read on every claim, so an operator may edit or remove entries while Ordin is running
+#[derive(Debug)]
+pub struct Registry {
+    path: PathBuf,
+    on_conflict: ConflictPolicy,
+    /// Serializes claims between concurrent requests, also across reloads
+    lock: Arc<Mutex<()>>,
+}
+
+impl Registry {
+    pub fn new(config: &RegistryConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            on_conflict: config.on_conflict,
+            lock: file_lock(&config.path),
+        }
+    }
+
+    /// Claim `fqdn` for a machine.
+    /// If the name is registered to another machine, the configured conflict policy decides the outcome
+    pub fn claim(
+        &self,
+        fqdn: &DomainName,
+        claim: Registration,
+        take_over: Option<TakeOver>,
+    ) -> Result<Claim, RegistryError> {
+        self.resolve(fqdn, claim, take_over, true)
+    }
+
+    /// Like `claim`, but without registering the name, for dry runs and jobs awaiting approval
+    pub fn check(
+        &self,
+        fqdn: &DomainName,
+        claim: Registration,
+        take_over: Option<TakeOver>,
+    ) -> Result<Claim, RegistryError> {
+        self.resolve(fqdn, claim, take_over, false)
+    }
+
+    fn resolve(
+        &self,
+        fqdn: &DomainName,
+        claim: Registration,
+        take_over: Option<TakeOver>,
+        register: bool,
+    ) -> Result<Claim, RegistryError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.read()?;
+
+        match entries.get(fqdn.as_str()) {
+            Some(existing) if existing.is_same_machine(&claim) => {
+                trace!("{} is already registered to {}", fqdn, existing.describe());
+                return Ok(Claim::Registered);
+            }
+            Some(existing) => match self.on_conflict {
+                ConflictPolicy::Override if take_over == Some(TakeOver::Requested) => warn!(
+                    "{} is registered to {}, overriding with {} as requested",
+                    fqdn,
+                    existing.describe(),
+                    claim.describe()
+                ),
+                ConflictPolicy::Approve if take_over == Some(TakeOver::Approved) => warn!(
+                    "{} is registered to {}, overriding with {} as approved",
+                    fqdn,
+                    existing.describe(),
+                    claim.describe()
+                ),
+                ConflictPolicy::Approve => {
+                    return Ok(Claim::NeedsApproval(format!(
+                        "{} is registered to {}",
+                        fqdn,
+                        existing.describe()
+                    )));
+                }
+                ConflictPolicy::Refuse | ConflictPolicy::Override => {
+                    warn!(
+                        "Refusing claim of {} by {}, it is registered to {}",
+                        fqdn,
+                        claim.describe(),
+                        existing.describe()
+                    );
+                    return Err(RegistryError::Conflict(fqdn.clone()));
+                }
+            },
+            None => trace!("Registering {} to {}", fqdn, claim.describe()),
+        }
+
+        if register {
+            entries.insert(fqdn.to_string(), claim);
+            self.write(&entries)?;
+        }
+        Ok(Claim::Registered)
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, Registration>, RegistryError> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+
+        let contents = fs::read(&self.path)?;
+        Ok(serde_json::from_slice(&contents)?)
+    }
+
+    fn write(&self, entries: &BTreeMap<String, Registration>) -> Result<(), RegistryError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = tmp_path(&self.path);
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec_pretty(entries)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
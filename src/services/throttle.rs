@@ -0,0 +1,140 @@
+use crate::config::ThrottleConfig;
+use crate::services::Target;
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The window of the global rate limit
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum ThrottleError {
+    #[error("Too many phone-homes, at most {0} jobs are started per minute")]
+    RateLimited(u32),
+}
+
+/// Whether a job should be started
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// A job for the same machine is running
+    Running,
+    /// A job for the same machine succeeded within the cool-down
+    CoolingDown,
+}
+
+#[derive(Debug)]
+enum JobState {
+    Running,
+    Succeeded(Instant),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The state of the last job of each machine, keyed by FQDN and address
+    jobs: HashMap<(String, String), JobState>,
+    /// When the jobs in the rate limit window were started
+    started: VecDeque<Instant>,
+}
+
+/// Deduplicates repeated phone-homes of the same machine and limits the rate at which jobs are started
+#[derive(Debug)]
+pub struct Throttle {
+    cooldown: Duration,
+    rate_limit: u32,
+    /// Shared with the throttles of reloaded configurations
+    state: Arc<Mutex<State>>,
+}
+
+impl Throttle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        Self {
+            cooldown: Duration::from_secs(config.cooldown),
+            rate_limit: config.rate_limit,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// A throttle with a new configuration, sharing the state of this one
+    pub fn reconfigure(&self, config: &ThrottleConfig) -> Self {
+        Self {
+            cooldown: Duration::from_secs(config.cooldown),
+            rate_limit: config.rate_limit,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Decide whether a job should be started for `target`.
+    /// If it is admitted, the job is considered running until `finish` is called
+    pub fn admit(&self, target: &Target) -> Result<Admission, ThrottleError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        let cooldown = self.cooldown;
+        state.jobs.retain(|_, job| match job {
+            JobState::Running => true,
+            JobState::Succeeded(at) => now.duration_since(*at) < cooldown,
+        });
+
+        match state.jobs.get(&key(target)) {
+            Some(JobState::Running) => {
+                debug!(
+                    "A job for {} ({}) is already running",
+                    target.fqdn, target.ip
+                );
+                return Ok(Admission::Running);
+            }
+            Some(JobState::Succeeded(_)) => {
+                debug!(
+                    "A job for {} ({}) succeeded within the cool-down",
+                    target.fqdn, target.ip
+                );
+                return Ok(Admission::CoolingDown);
+            }
+            None => {}
+        }
+
+        if self.rate_limit > 0 {
+            while let Some(at) = state.started.front() {
+                if now.duration_since(*at) < RATE_WINDOW {
+                    break;
+                }
+                state.started.pop_front();
+            }
+
+            if state.started.len() >= self.rate_limit as usize {
+                warn!("Rate limit reached, refusing job for {}", target.fqdn);
+                return Err(ThrottleError::RateLimited(self.rate_limit));
+            }
+            state.started.push_back(now);
+        }
+
+        state.jobs.insert(key(target), JobState::Running);
+        Ok(Admission::Admitted)
+    }
+
+    /// Mark a job for `target` as running, without checking for duplicates or the rate limit
+    pub fn start(&self, target: &Target) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.jobs.insert(key(target), JobState::Running);
+    }
+
+    /// Mark the job for `target` as finished.
+    /// Failed jobs may be retried right away, successful jobs only after the cool-down
+    pub fn finish(&self, target: &Target, success: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if success && !self.cooldown.is_zero() {
+            state
+                .jobs
+                .insert(key(target), JobState::Succeeded(Instant::now()));
+        } else {
+            state.jobs.remove(&key(target));
+        }
+    }
+}
+
+fn key(target: &Target) -> (String, String) {
+    (target.fqdn.to_string(), target.ip.clone())
+}
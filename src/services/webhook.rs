@@ -0,0 +1,301 @@
+use crate::config::{WebhookConfig, WebhookTrigger};
+use crate::services::{Service, Target};
+use crate::util::render_template;
+use hmac::{Hmac, Mac};
+use log::{debug, trace, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Webhook returned HTTP status {0}")]
+    Status(u16),
+    #[error("Failed to send webhook {0:?}")]
+    Transport(#[from] Box<ureq::Transport>),
+    #[error("Failed to serialize JSON {0:?}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid HMAC secret")]
+    Secret,
+}
+
+/// The outcome of the services run before the webhook
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Success,
+    Failure(String),
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure(_) => "failure",
+        }
+    }
+
+    fn error(&self) -> Option<&str> {
+        match self {
+            Self::Success => None,
+            Self::Failure(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    hostname: &'a str,
+    ip: &'a str,
+    addresses: &'a [IpAddr],
+    fqdn: &'a str,
+    outcome: &'a str,
+    error: Option<&'a str>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookService {
+    url: String,
+    body: Option<String>,
+    headers: HashMap<String, String>,
+    secret: Option<String>,
+    trigger: WebhookTrigger,
+    retries: u32,
+    retry_delay: Duration,
+    agent: ureq::Agent,
+}
+
+impl WebhookService {
+    pub fn new(config: &WebhookConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+
+        Self {
+            url: config.url.clone(),
+            body: config.body.clone(),
+            headers: config.headers.clone(),
+            secret: config.secret.clone(),
+            trigger: config.on,
+            retries: config.retries,
+            retry_delay: Duration::from_secs(config.retry_delay),
+            agent,
+        }
+    }
+
+    /// Whether the webhook should be called for the provided outcome
+    pub fn triggers_on(&self, outcome: &Outcome) -> bool {
+        matches!(
+            (self.trigger, outcome),
+            (WebhookTrigger::Always, _)
+                | (WebhookTrigger::Success, Outcome::Success)
+                | (WebhookTrigger::Failure, Outcome::Failure(_))
+        )
+    }
+
+    /// Call the webhook for the target, retrying on server and transport errors
+    pub fn notify(&self, target: &Target, outcome: &Outcome) -> Result<(), WebhookError> {
+        debug!("Calling webhook {} for {:?}", &self.url, target);
+
+        let vars = [
+            ("hostname", target.hostname.as_str()),
+            ("ip", target.ip.as_str()),
+            ("fqdn", target.fqdn.as_str()),
+            ("outcome", outcome.as_str()),
+            ("error", outcome.error().unwrap_or("")),
+        ];
+
+        let body = match &self.body {
+            Some(template) => {
+                // The body is JSON, so the values must not be able to break out of their strings
+                let escaped = vars
+                    .iter()
+                    .map(|(name, value)| Ok((*name, json_escape(value)?)))
+                    .collect::<Result<Vec<_>, WebhookError>>()?;
+                let escaped = escaped
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect::<Vec<_>>();
+                render_template(template, &escaped)
+            }
+            None => serde_json::to_string(&Payload {
+                hostname: &target.hostname,
+                ip: &target.ip,
+                addresses: &target.addresses,
+                fqdn: target.fqdn.as_str(),
+                outcome: outcome.as_str(),
+                error: outcome.error(),
+            })?,
+        };
+
+        let signature = match &self.secret {
+            Some(secret) => Some(self.sign(secret, &body)?),
+            None => None,
+        };
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            trace!("Webhook attempt {}/{}", attempt, self.retries + 1);
+
+            let mut request = self
+                .agent
+                .post(&render_template(&self.url, &vars))
+                .set("Content-Type", "application/json");
+            if let Some(signature) = &signature {
+                request = request.set("X-Ordin-Signature", signature);
+            }
+            for (name, value) in &self.headers {
+                request = request.set(name, &render_template(value, &vars));
+            }
+
+            let err = match request.send_string(&body) {
+                Ok(_) => {
+                    trace!("Webhook completed successfully");
+                    return Ok(());
+                }
+                Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                    return Err(WebhookError::Status(code));
+                }
+                Err(ureq::Error::Status(code, _)) => WebhookError::Status(code),
+                Err(ureq::Error::Transport(t)) => WebhookError::Transport(Box::new(t)),
+            };
+
+            if attempt > self.retries {
+                return Err(err);
+            }
+
+            warn!(
+                "Webhook {} failed: {:?}. Retrying in {:?}",
+                &self.url, err, self.retry_delay
+            );
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    fn sign(&self, secret: &str, body: &str) -> Result<String, WebhookError> {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).map_err(|_| WebhookError::Secret)?;
+        mac.update(body.as_bytes());
+        Ok(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+}
+
+/// Escape `value` for use inside a JSON string, without the surrounding quotes
+fn json_escape(value: &str) -> Result<String, WebhookError> {
+    let quoted = serde_json::to_string(value)?;
+    Ok(quoted[1..quoted.len() - 1].to_string())
+}
+
+impl Service for WebhookService {
+    type Err = WebhookError;
+
+    fn run(&self, target: &Target) -> Result<(), Self::Err> {
+        if !self.triggers_on(&Outcome::Success) {
+            return Ok(());
+        }
+
+        self.notify(target, &Outcome::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::mpsc;
+
+    /// A mock server answering one request to `/hook` with each of `statuses`
+    fn mock_server(statuses: &[u16]) -> (String, mpsc::Receiver<testing::Received>) {
+        let (url, rx) = testing::mock_server(statuses);
+        (format!("{}/hook", url), rx)
+    }
+
+    fn webhook(url: &str, body: Option<&str>, secret: Option<&str>) -> WebhookService {
+        WebhookService::new(&WebhookConfig {
+            url: url.to_string(),
+            body: body.map(str::to_string),
+            headers: HashMap::new(),
+            secret: secret.map(str::to_string),
+            on: WebhookTrigger::Always,
+            retries: 2,
+            retry_delay: 0,
+            timeout: 5,
+        })
+    }
+
+    fn target(hostname: &str) -> Target {
+        Target::new(
+            "10.0.0.5",
+            vec!["10.0.0.5".parse().unwrap()],
+            hostname,
+            "foo.example.com".parse().unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn signs_body() {
+        let (url, rx) = mock_server(&[200]);
+        webhook(&url, None, Some("bar"))
+            .notify(&target("foo"), &Outcome::Success)
+            .unwrap();
+
+        let received = rx.recv().unwrap();
+        let mut mac = HmacSha256::new_from_slice(b"bar").unwrap();
+        mac.update(received.body.as_bytes());
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert!(received
+            .headers
+            .contains(&("x-ordin-signature".to_string(), expected)));
+    }
+
+    #[test]
+    fn escapes_template_values() {
+        let (url, rx) = mock_server(&[200]);
+        let body = r#"{"host": "{{hostname}}", "error": "{{error}}"}"#;
+        webhook(&url, Some(body), None)
+            .notify(
+                &target(r#"foo", "admin": "true"#),
+                &Outcome::Failure(r#"IO error "denied""#.to_string()),
+            )
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&rx.recv().unwrap().body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "host": r#"foo", "admin": "true"#,
+                "error": r#"IO error "denied""#
+            })
+        );
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, rx) = mock_server(&[503, 500, 200]);
+        webhook(&url, None, None)
+            .notify(&target("foo"), &Outcome::Success)
+            .unwrap();
+
+        assert_eq!(rx.iter().take(3).count(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, rx) = mock_server(&[422, 200]);
+        let result = webhook(&url, None, None).notify(&target("foo"), &Outcome::Success);
+
+        assert!(matches!(result, Err(WebhookError::Status(422))));
+        rx.recv().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures shared by the unit tests
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+/// A received HTTP request: the request line, headers and body
+pub struct Received {
+    pub request_line: String,
+    /// The header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Start a local HTTP server answering one request with each of `statuses`, in order.
+/// Returns the base URL of the server and a receiver for the requests it received
+pub fn mock_server(statuses: &[u16]) -> (String, mpsc::Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    let statuses = statuses.to_vec();
+
+    thread::spawn(move || {
+        for status in statuses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+
+                let (name, value) = line.split_once(':').unwrap();
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+            }
+
+            let length = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map(|(_, value)| value.parse::<usize>().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+
+            tx.send(Received {
+                request_line: request_line.trim_end().to_string(),
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            })
+            .unwrap();
+        }
+    });
+
+    (url, rx)
+}
@@ -0,0 +1,131 @@
+use crate::config::TlsConfig;
+use log::{error, info, trace};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert, RootCertStore,
+    ServerConfig,
+};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("No certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("No private key found in {0:?}")]
+    NoKey(PathBuf),
+    #[error("Unsupported private key in {0:?}")]
+    UnsupportedKey(PathBuf),
+    #[error("Invalid CA certificates in {0:?}")]
+    InvalidCa(PathBuf),
+}
+
+/// Resolves the server certificate, which can be reloaded from disk while the server is running
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(config: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(Self {
+            current: RwLock::new(load_certified_key(&config.cert, &config.key)?),
+            cert: config.cert.clone(),
+            key: config.key.clone(),
+        })
+    }
+
+    /// Load the certificate and key from disk again.
+    /// If loading fails, the current certificate stays in use
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified_key = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = certified_key;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+/// Create the rustls server configuration.
+/// Returns the configuration, and the certificate resolver used by it
+pub fn server_config(config: &TlsConfig) -> Result<(ServerConfig, Arc<CertResolver>), TlsError> {
+    let verifier = match &config.client_ca {
+        Some(path) => {
+            trace!("Requiring client certificates signed by {:?}", path);
+            let mut roots = RootCertStore::empty();
+            let mut reader = BufReader::new(fs::File::open(path)?);
+            match roots.add_pem_file(&mut reader) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => return Err(TlsError::InvalidCa(path.clone())),
+            }
+
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let resolver = Arc::new(CertResolver::new(config)?);
+    let mut server_config = ServerConfig::new(verifier);
+    server_config.cert_resolver = resolver.clone();
+    Ok((server_config, resolver))
+}
+
+/// Reload the certificate whenever the process receives SIGHUP
+pub fn reload_on_sighup(resolver: Arc<CertResolver>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::Builder::new()
+        .name("tls-reload".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                match resolver.reload() {
+                    Ok(_) => info!("Reloaded TLS certificate"),
+                    Err(e) => error!("Failed to reload TLS certificate: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    trace!("Loading TLS certificate from {:?}", cert);
+    let certs = pemfile::certs(&mut BufReader::new(fs::File::open(cert)?))
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| TlsError::NoCertificates(cert.to_path_buf()))?;
+
+    trace!("Loading TLS private key from {:?}", key);
+    let contents = fs::read(key)?;
+    let private_key = pemfile::pkcs8_private_keys(&mut contents.as_slice())
+        .ok()
+        .and_then(|keys| keys.into_iter().next())
+        .or_else(|| {
+            pemfile::rsa_private_keys(&mut contents.as_slice())
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+        })
+        .ok_or_else(|| TlsError::NoKey(key.to_path_buf()))?;
+
+    let signing_key = sign::any_supported_type(&private_key)
+        .map_err(|_| TlsError::UnsupportedKey(key.to_path_buf()))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
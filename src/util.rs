@@ -1,6 +1,19 @@
+use lazy_static::lazy_static;
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
 use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::{env, fs};
+
+lazy_static! {
+    static ref FILE_LOCKS: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
 
 pub struct Ready<T>(Option<T>);
 
@@ -20,3 +33,111 @@ impl<T> Future for Ready<T> {
         Poll::Ready(self.0.take().expect("Ready polled after completion"))
     }
 }
+
+/// Replace every `{{name}}` placeholder in `template` with the value of the matching variable.
+/// Placeholders without a matching variable are left untouched
+pub fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter()
+        .fold(template.to_string(), |acc, (name, value)| {
+            acc.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+/// Bind a TCP listener to `addr`.
+/// IPv6 listeners only accept IPv6 connections, so IPv4 and IPv6 listeners on the same port can coexist
+pub fn bind_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Whether binding a listener failed because the host does not support the address family,
+/// e.g. IPv6 on a host or container with IPv6 disabled
+pub fn is_unsupported_family(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::AddrNotAvailable
+        || matches!(
+            e.raw_os_error(),
+            Some(libc::EAFNOSUPPORT) | Some(libc::EPROTONOSUPPORT)
+        )
+}
+
+/// Compare two byte strings in time independent of their contents
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Find an executable program. Paths containing a slash are used as they are, other names are looked up in `PATH`
+pub fn find_executable(program: &Path) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.components().count() > 1 {
+        return is_executable(program).then(|| program.to_path_buf());
+    }
+
+    env::var_os("PATH").and_then(|path| {
+        env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| is_executable(candidate))
+    })
+}
+
+/// Check that the file at `path` can be written to, or if it does not exist, that its directory exists
+pub fn check_writable(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        fs::OpenOptions::new().append(true).open(path)?;
+        return Ok(());
+    }
+
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Directory {:?} does not exist", parent),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The lock serializing read-modify-write updates of the file at `path`.
+/// There is one lock per file for the whole process, so services of reloaded configurations
+/// share the lock with the services running jobs of the previous configuration
+pub fn file_lock(path: &Path) -> Arc<Mutex<()>> {
+    // The file itself may not exist yet, so the directory is canonicalized instead
+    let key = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            fs::canonicalize(parent)
+                .map(|parent| parent.join(name))
+                .unwrap_or_else(|_| path.to_path_buf())
+        }
+        _ => path.to_path_buf(),
+    };
+
+    FILE_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(key)
+        .or_default()
+        .clone()
+}
+
+/// The temporary file a new version of the file at `path` is written to before it is renamed over it.
+/// The suffix is appended to the whole file name, so `db.example.com` and `db.example.net` don't share one
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ordin-tmp");
+    path.with_file_name(name)
+}
@@ -0,0 +1,261 @@
+use crate::config::{Config, ConflictPolicy, DnsBackendConfig, PipelineEntry, ZoneConfig};
+use crate::services::naming::DomainName;
+use crate::util::find_executable;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// A problem in the configuration, e.g. a missing file or an empty required field
+#[derive(Debug)]
+pub struct Problem {
+    /// The key the problem was found at, e.g. `dns.zones.0.server`
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Check the configuration, returning all problems found.
+/// `unknown_keys` are the keys which are not part of the configuration format, see `Config::read`
+pub fn validate(config: &Config, unknown_keys: &[String]) -> Vec<Problem> {
+    let mut v = Validator::default();
+
+    for key in unknown_keys {
+        v.push(key, "Unknown key");
+    }
+
+    if v.required("global.domain", &config.global.domain) {
+        v.domain("global.domain", &config.global.domain);
+    }
+
+    if let Some(tls) = &config.global.tls {
+        v.file("global.tls.cert", &tls.cert);
+        v.file("global.tls.key", &tls.key);
+        if let Some(client_ca) = &tls.client_ca {
+            v.file("global.tls.client_ca", client_ca);
+        }
+    }
+
+    if let Some(auth) = &config.auth {
+        v.required("auth.secret", &auth.secret);
+    }
+
+    if let Some(approval) = &config.approval {
+        v.required("approval.admin_token", &approval.admin_token);
+        for (index, zone) in approval.zones.iter().enumerate() {
+            v.domain(&format!("approval.zones.{}", index), zone);
+        }
+    }
+
+    if let Some(registry) = &config.registry {
+        v.parent_directory("registry.path", &registry.path);
+        if registry.on_conflict == ConflictPolicy::Override && config.auth.is_none() {
+            v.push(
+                "registry.on_conflict",
+                "The 'override' policy requires [auth], otherwise names can't be taken over",
+            );
+        }
+    }
+
+    if let Some(dns) = &config.dns {
+        if dns.zones.is_empty() {
+            // The single zone is named after the global domain unless zone_name is set
+            if !dns.zone_name.is_empty() {
+                v.domain("dns.zone_name", &dns.zone_name);
+            }
+            for zone in dns.zones(&config.global) {
+                validate_backend(&mut v, "dns", &zone);
+            }
+        }
+
+        for (index, zone) in dns.zones.iter().enumerate() {
+            validate_zone(&mut v, &format!("dns.zones.{}", index), zone);
+        }
+    }
+
+    if let Some(ansible) = &config.ansible {
+        if v.required("ansible.inventory", &ansible.inventory.to_string_lossy()) {
+            v.parent_directory("ansible.inventory", &ansible.inventory);
+        }
+
+        for (index, playbook) in ansible.playbooks.iter().enumerate() {
+            v.file(&format!("ansible.playbooks.{}", index), playbook);
+        }
+
+        if let Some(binary) = &ansible.ansible_playbook_binary {
+            v.executable("ansible.ansible_playbook_binary", binary);
+        }
+    }
+
+    for (index, entry) in config.pipeline.iter().enumerate() {
+        let key = format!("pipeline.{}", index);
+        match entry {
+            PipelineEntry::Dns if config.dns.is_none() => {
+                v.push(&key, "The dns service requires a [dns] section")
+            }
+            PipelineEntry::Ansible if config.ansible.is_none() => {
+                v.push(&key, "The ansible service requires an [ansible] section")
+            }
+            PipelineEntry::Webhook(webhook) => {
+                v.url(&format!("{}.url", key), &webhook.url);
+            }
+            PipelineEntry::Command(command) => {
+                let program = match &command.workdir {
+                    Some(workdir) => {
+                        v.directory(&format!("{}.workdir", key), workdir);
+                        if command.command.is_relative() && command.command.components().count() > 1
+                        {
+                            workdir.join(&command.command)
+                        } else {
+                            command.command.clone()
+                        }
+                    }
+                    None => command.command.clone(),
+                };
+                v.executable(&format!("{}.command", key), &program);
+            }
+            _ => {}
+        }
+    }
+
+    v.problems
+}
+
+fn validate_zone(v: &mut Validator, prefix: &str, zone: &ZoneConfig) {
+    let key = |field: &str| format!("{}.{}", prefix, field);
+
+    if v.required(&key("zone_name"), &zone.zone_name) {
+        v.domain(&key("zone_name"), &zone.zone_name);
+    }
+    if let Some(domain) = &zone.domain {
+        v.domain(&key("domain"), domain);
+    }
+    for (index, suffix) in zone.suffixes.iter().enumerate() {
+        v.domain(&key(&format!("suffixes.{}", index)), suffix);
+    }
+
+    validate_backend(v, prefix, zone);
+}
+
+fn validate_backend(v: &mut Validator, prefix: &str, zone: &ZoneConfig) {
+    let key = |field: &str| format!("{}.{}", prefix, field);
+
+    match &zone.backend {
+        DnsBackendConfig::Nsupdate => {
+            if v.required(&key("server"), &zone.server) {
+                v.server(&key("server"), &zone.server);
+            }
+            if let Some(key_file) = &zone.key_file {
+                v.file(&key("key_file"), key_file);
+            }
+        }
+        DnsBackendConfig::Hosts(hosts) => {
+            v.parent_directory(&key("backend.path"), &hosts.path);
+            match (&hosts.reload_signal, &hosts.pid_file) {
+                (Some(_), Some(pid_file)) => v.parent_directory(&key("backend.pid_file"), pid_file),
+                (Some(_), None) => v.push(&key("backend.reload_signal"), "Requires pid_file"),
+                _ => {}
+            }
+        }
+        DnsBackendConfig::Zonefile(zonefile) => {
+            v.file(&key("backend.path"), &zonefile.path);
+        }
+        DnsBackendConfig::Powerdns(powerdns) => {
+            v.url(&key("backend.url"), &powerdns.url);
+            v.required(&key("backend.api_key"), &powerdns.api_key);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    problems: Vec<Problem>,
+}
+
+impl Validator {
+    fn push<S: ToString>(&mut self, key: &str, message: S) {
+        self.problems.push(Problem {
+            key: key.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Returns whether the value is set, so further checks can be skipped if it is not
+    fn required(&mut self, key: &str, value: &str) -> bool {
+        if value.trim().is_empty() {
+            self.push(key, "Must not be empty");
+            return false;
+        }
+        true
+    }
+
+    fn domain(&mut self, key: &str, value: &str) {
+        if let Err(e) = value.parse::<DomainName>() {
+            self.push(key, format!("Invalid domain name {:?}: {}", value, e));
+        }
+    }
+
+    fn file(&mut self, key: &str, path: &Path) {
+        if !path.is_file() {
+            self.push(key, format!("File {:?} does not exist", path));
+        }
+    }
+
+    fn directory(&mut self, key: &str, path: &Path) {
+        if !path.is_dir() {
+            self.push(key, format!("Directory {:?} does not exist", path));
+        }
+    }
+
+    /// For files Ordin creates, the directory they are created in must exist
+    fn parent_directory(&mut self, key: &str, path: &Path) {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+                self.push(key, format!("Directory {:?} does not exist", parent))
+            }
+            _ => {}
+        }
+    }
+
+    fn executable(&mut self, key: &str, path: &Path) {
+        if find_executable(path).is_none() {
+            self.push(key, format!("Program {:?} was not found", path));
+        }
+    }
+
+    fn url(&mut self, key: &str, value: &str) {
+        if !value.starts_with("http://") && !value.starts_with("https://") {
+            self.push(key, format!("Invalid URL {:?}, expected http(s)://", value));
+        }
+    }
+
+    /// A DNS server given as `<host> [port]`, like nsupdate's `server` command
+    fn server(&mut self, key: &str, value: &str) {
+        let mut parts = value.split_whitespace();
+        let host = parts.next().unwrap_or_default();
+
+        // Anything that looks like an address has to be one, everything else must be a hostname
+        let looks_like_address =
+            host.contains(':') || host.chars().all(|c| c.is_ascii_digit() || c == '.');
+        if looks_like_address {
+            if host.parse::<IpAddr>().is_err() {
+                self.push(key, format!("Invalid IP address {:?}", host));
+            }
+        } else if let Err(e) = host.parse::<DomainName>() {
+            self.push(key, format!("Invalid hostname {:?}: {}", host, e));
+        }
+
+        if let Some(port) = parts.next() {
+            if port.parse::<u16>().is_err() {
+                self.push(key, format!("Invalid port {:?}", port));
+            }
+        }
+        if parts.next().is_some() {
+            self.push(key, format!("Expected '<host> [port]', got {:?}", value));
+        }
+    }
+}